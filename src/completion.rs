@@ -0,0 +1,348 @@
+use clap::{Arg, Command};
+use reedline::{Completer, Span, Suggestion};
+
+use crate::{
+    queue::Queue,
+    utils::{playlist_dir, playlists_dir},
+};
+
+///Completes REPL input from clap definition of commands, playlists and their tracks
+pub struct ReplCompleter {
+    command: Command,
+}
+impl ReplCompleter {
+    pub fn new(command: Command) -> Self {
+        Self { command }
+    }
+    ///Candidates with optional description for `current` word typed after `words`
+    fn candidates(&self, words: &[Word], current: &str) -> Vec<(String, Option<String>)> {
+        let Some((first, mut rest)) = words.split_first() else {
+            return subcommands(&self.command);
+        };
+        let Some(mut sub) = self.command.find_subcommand(&first.text) else {
+            return Vec::new();
+        };
+        if sub.has_subcommands() {
+            match rest.split_first() {
+                None => return subcommands(sub),
+                Some((nested, nested_rest)) => {
+                    if let Some(nested) = sub.find_subcommand(&nested.text) {
+                        sub = nested;
+                        rest = nested_rest;
+                    }
+                }
+            }
+        }
+
+        let parsed = Parsed::new(sub, rest);
+        if current.starts_with('-') && parsed.pending.is_none() {
+            return flags(sub);
+        }
+        let arg = match parsed.pending {
+            Some(arg) => arg,
+            None => {
+                let positionals: Vec<&Arg> = sub.get_positionals().collect();
+                match positionals.get(parsed.positionals).copied().or_else(|| {
+                    positionals
+                        .last()
+                        .copied()
+                        .filter(|arg| arg.get_num_args().is_some_and(|n| n.max_values() > 1))
+                }) {
+                    Some(arg) => arg,
+                    None => return flags(sub),
+                }
+            }
+        };
+
+        let possible = arg.get_possible_values();
+        if !possible.is_empty() {
+            return possible
+                .iter()
+                .map(|v| (v.get_name().to_string(), None))
+                .collect();
+        }
+        let playlist = || {
+            parsed
+                .value_of("playlist_name")
+                .or_else(|| (sub.get_name() == "play").then(|| parsed.value_of("name"))?)
+        };
+        match (sub.get_name(), arg.get_id().as_str()) {
+            (_, "playlist_name") | ("play" | "download-result", "name") => playlist_names()
+                .into_iter()
+                .map(|name| (name, None))
+                .collect(),
+            ("rename", "current_name") | ("remove-audio" | "trim", "name") => {
+                track_names(playlist())
+                    .into_iter()
+                    .map(|name| (name, None))
+                    .collect()
+            }
+            ("play", "track") => track_names(playlist())
+                .into_iter()
+                .enumerate()
+                .map(|(i, name)| ((i + 1).to_string(), Some(name)))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+impl Completer for ReplCompleter {
+    fn complete(&mut self, line: &str, pos: usize) -> Vec<Suggestion> {
+        let mut words = split_words(&line[..pos]);
+        let Some(current) = words.pop() else {
+            return Vec::new();
+        };
+        let prefix = current.text.to_lowercase();
+        let span = Span::new(current.start, pos);
+
+        self.candidates(&words, &current.text)
+            .into_iter()
+            .filter(|(value, _)| value.to_lowercase().starts_with(&prefix))
+            .map(|(value, description)| Suggestion {
+                value: shell_words::quote(&value).into_owned(),
+                description,
+                span,
+                append_whitespace: true,
+                ..Default::default()
+            })
+            .collect()
+    }
+}
+
+///Word of input, `start` is where it begins in line, including opening quote
+struct Word {
+    start: usize,
+    text: String,
+}
+
+///Splits input like shell would, but tolerates unfinished quotes. Last word is the one being
+///typed, empty when input ends with whitespace
+fn split_words(line: &str) -> Vec<Word> {
+    let mut words = Vec::new();
+    let mut current: Option<Word> = None;
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' && quote != Some('\'') {
+            escaped = true;
+            current.get_or_insert(Word {
+                start: i,
+                text: String::new(),
+            });
+            continue;
+        } else if Some(c) == quote {
+            quote = None;
+            continue;
+        } else if quote.is_none() && (c == '"' || c == '\'') {
+            quote = Some(c);
+            current.get_or_insert(Word {
+                start: i,
+                text: String::new(),
+            });
+            continue;
+        } else if quote.is_none() && c.is_whitespace() {
+            words.extend(current.take());
+            continue;
+        }
+        current
+            .get_or_insert(Word {
+                start: i,
+                text: String::new(),
+            })
+            .text
+            .push(c);
+    }
+    words.push(current.unwrap_or(Word {
+        start: line.len(),
+        text: String::new(),
+    }));
+    words
+}
+
+///Values given so far to arguments of subcommand
+struct Parsed<'a> {
+    values: Vec<(&'a Arg, String)>,
+    ///Option whose value is being typed
+    pending: Option<&'a Arg>,
+    ///How many positional values were given
+    positionals: usize,
+}
+impl<'a> Parsed<'a> {
+    fn new(command: &'a Command, words: &[Word]) -> Self {
+        let positionals: Vec<&Arg> = command.get_positionals().collect();
+        let mut parsed = Self {
+            values: Vec::new(),
+            pending: None,
+            positionals: 0,
+        };
+        for word in words {
+            if let Some(arg) = parsed.pending.take() {
+                parsed.values.push((arg, word.text.clone()));
+            } else if word.text.starts_with('-') {
+                parsed.pending =
+                    find_option(command, &word.text).filter(|arg| arg.get_action().takes_values());
+            } else {
+                if let Some(arg) = positionals.get(parsed.positionals) {
+                    parsed.values.push((arg, word.text.clone()));
+                }
+                parsed.positionals += 1;
+            }
+        }
+        parsed
+    }
+    fn value_of(&self, id: &str) -> Option<String> {
+        self.values
+            .iter()
+            .find(|(arg, _)| arg.get_id() == id)
+            .map(|(_, value)| value.clone())
+    }
+}
+
+fn find_option<'a>(command: &'a Command, word: &str) -> Option<&'a Arg> {
+    command.get_arguments().find(|arg| {
+        arg.get_long()
+            .is_some_and(|long| word == format!("--{long}"))
+            || arg
+                .get_short()
+                .is_some_and(|short| word == format!("-{short}"))
+    })
+}
+
+fn subcommands(command: &Command) -> Vec<(String, Option<String>)> {
+    command
+        .get_subcommands()
+        .map(|sub| {
+            (
+                sub.get_name().to_string(),
+                sub.get_about().map(|about| about.to_string()),
+            )
+        })
+        .collect()
+}
+
+fn flags(command: &Command) -> Vec<(String, Option<String>)> {
+    command
+        .get_arguments()
+        .filter_map(|arg| {
+            let flag = arg.get_long().map(|long| format!("--{long}"))?;
+            Some((flag, arg.get_help().map(|help| help.to_string())))
+        })
+        .collect()
+}
+
+///Completion runs inside synchronous reedline callback, so playlists are read without tokio
+fn playlist_names() -> Vec<String> {
+    let Some(entries) = playlists_dir().and_then(std::fs::read_dir).ok() else {
+        return Vec::new();
+    };
+    let mut names: Vec<String> = entries
+        .flatten()
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_dir()))
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    names
+}
+
+fn track_names(playlist: Option<String>) -> Vec<String> {
+    let queue = playlist
+        .and_then(|playlist| playlist_dir(&playlist).ok())
+        .and_then(|dir| std::fs::read(dir.join("queue.json")).ok())
+        .and_then(|buf| serde_json::from_slice::<Queue>(&buf).ok());
+    queue
+        .map(|queue| queue.items.into_iter().map(|item| item.name).collect())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+    use crate::{Cli, queue::QueueItem};
+
+    fn texts(line: &str) -> Vec<(usize, String)> {
+        split_words(line)
+            .into_iter()
+            .map(|word| (word.start, word.text))
+            .collect()
+    }
+
+    #[test]
+    fn splits_words_like_shell() {
+        assert_eq!(
+            texts(r#"play "my mix" --tr"#),
+            [
+                (0, "play".into()),
+                (5, "my mix".into()),
+                (14, "--tr".into())
+            ]
+        );
+        assert_eq!(texts("play "), [(0, "play".into()), (5, String::new())]);
+        assert_eq!(
+            texts("play 'my mi"),
+            [(0, "play".into()), (5, "my mi".into())]
+        );
+        assert_eq!(
+            texts(r"play my\ mix"),
+            [(0, "play".into()), (5, "my mix".into())]
+        );
+        assert_eq!(texts(""), [(0, String::new())]);
+    }
+
+    #[test]
+    fn parses_given_values() {
+        let command = Cli::command();
+        let play = command.find_subcommand("play").unwrap();
+        let words = split_words("mix --track ");
+        let parsed = Parsed::new(play, &words[..words.len() - 1]);
+        assert_eq!(parsed.value_of("name").as_deref(), Some("mix"));
+        assert_eq!(
+            parsed.pending.map(|arg| arg.get_id().as_str()),
+            Some("track")
+        );
+        assert_eq!(parsed.positionals, 1);
+
+        let words = split_words("mix --track 2 ");
+        let parsed = Parsed::new(play, &words[..words.len() - 1]);
+        assert!(parsed.pending.is_none());
+        assert_eq!(parsed.value_of("track").as_deref(), Some("2"));
+    }
+
+    #[tokio::test]
+    async fn completes_playlists_and_tracks_quoted() {
+        let playlist = "completion mix";
+        crate::utils::create_playlist(playlist).await.unwrap();
+        for name in ["Intro song", "Outro"] {
+            Queue::append_to_json(playlist, QueueItem::new(format!("{name}.mp3"), name.into()))
+                .await
+                .unwrap();
+        }
+        let mut completer = ReplCompleter::new(Cli::command());
+        let complete = |completer: &mut ReplCompleter, line: &str| -> Vec<Suggestion> {
+            completer.complete(line, line.len())
+        };
+
+        let suggestions = complete(&mut completer, "play complet");
+        assert_eq!(suggestions.len(), 1);
+        assert_eq!(suggestions[0].value, "'completion mix'");
+        assert_eq!(suggestions[0].span, Span::new(5, 12));
+
+        let suggestions = complete(&mut completer, "play 'completion mix' --track ");
+        let values: Vec<(&str, Option<&str>)> = suggestions
+            .iter()
+            .map(|s| (s.value.as_str(), s.description.as_deref()))
+            .collect();
+        assert_eq!(values, [("1", Some("Intro song")), ("2", Some("Outro"))]);
+
+        let suggestions = complete(
+            &mut completer,
+            "rename \"completion mix\" --current-name int",
+        );
+        assert_eq!(suggestions.len(), 1);
+        assert_eq!(suggestions[0].value, "'Intro song'");
+        assert_eq!(suggestions[0].span, Span::new(39, 42));
+    }
+}
@@ -0,0 +1,192 @@
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr},
+    path::PathBuf,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{fs, io::AsyncReadExt, io::AsyncWriteExt};
+
+use crate::{
+    equalizer::EqSettings,
+    keybindings::{KeyAction, default_keybindings},
+    loudness::REFERENCE_LUFS,
+    output::OutputTarget,
+    utils::get_default_path,
+};
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SleepAction {
+    Pause,
+    Stop,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Normalization {
+    Off,
+    Track,
+    Album,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputBackend {
+    Device,
+    Null,
+    Wav,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct Config {
+    ///How long the volume ramps down before the sleep timer pauses playback
+    pub sleep_fade_secs: u64,
+    ///What happens once the sleep timer fade is done
+    pub sleep_action: SleepAction,
+    ///Which gain measured by `analyze` gets applied during playback
+    pub normalization: Normalization,
+    ///Loudness normalized audio is brought to
+    pub target_lufs: f64,
+    pub eq: EqSettings,
+    ///Name of output device, default one is used when missing
+    pub output_device: Option<String>,
+    ///Where playback goes, `null` and `wav` work without sound card
+    pub output_backend: OutputBackend,
+    ///How many times faster than real time null backend consumes audio
+    pub null_speed: f32,
+    ///File written by wav backend, `output.wav` in working directory when missing
+    pub wav_path: Option<PathBuf>,
+    ///Use fast flat search even without `--flat`
+    pub flat_search: bool,
+    ///How many REPL lines are kept in `history` file
+    pub history_size: usize,
+    ///Don't store REPL lines containing URLs in history
+    pub history_exclude_urls: bool,
+    ///Keys like `ctrl-p` controlling playback right from prompt, even with half typed command
+    pub keybindings: BTreeMap<String, KeyAction>,
+    ///How far `seek-forward` and `seek-backward` keys move
+    pub seek_step_secs: u64,
+    ///Port `daemon` serves MPD clients on, only from localhost. Off when missing
+    pub mpd_port: Option<u16>,
+    ///Port `daemon` serves HTTP API and web remote on. Off when missing
+    pub http_port: Option<u16>,
+    ///Address HTTP server binds to, `0.0.0.0` makes it reachable from LAN
+    pub http_address: IpAddr,
+    ///Needed by HTTP clients other than localhost, generated when first needed
+    pub http_token: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            sleep_fade_secs: 10,
+            sleep_action: SleepAction::Pause,
+            normalization: Normalization::Track,
+            target_lufs: REFERENCE_LUFS,
+            eq: EqSettings::default(),
+            output_device: None,
+            output_backend: OutputBackend::Device,
+            null_speed: 1.0,
+            wav_path: None,
+            flat_search: false,
+            history_size: 1000,
+            history_exclude_urls: false,
+            keybindings: default_keybindings(),
+            seek_step_secs: 10,
+            mpd_port: None,
+            http_port: None,
+            http_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            http_token: None,
+        }
+    }
+}
+
+impl Config {
+    pub async fn load() -> Result<Self, std::io::Error> {
+        let target_path = get_config_path().await?;
+        let mut file = match fs::File::open(target_path).await {
+            Ok(v) => v,
+            Err(_) => {
+                let config = Config::default();
+                config.save().await?;
+                return Ok(config);
+            }
+        };
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf).await?;
+        let config: Config = serde_json::from_slice(&buf)?;
+        if !config.target_lufs.is_finite() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "target_lufs has to be a finite number",
+            ));
+        }
+        if !config.eq.is_finite() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "eq gains have to be finite numbers",
+            ));
+        }
+        if !valid_speed(config.null_speed) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "null_speed has to be a finite number greater than 0",
+            ));
+        }
+        Ok(config)
+    }
+    pub async fn save(&self) -> Result<(), std::io::Error> {
+        //Written next to config and renamed over it, so a failure leaves old config in place
+        let bytes = serde_json::to_vec_pretty(self)?;
+        let target_path = get_config_path().await?;
+        let temp_path = target_path.with_extension("json.tmp");
+        let mut file = fs::File::create(&temp_path).await?;
+        file.write_all(&bytes).await?;
+        file.sync_all().await?;
+        fs::rename(temp_path, target_path).await
+    }
+    pub fn sleep_fade(&self) -> Duration {
+        Duration::from_secs(self.sleep_fade_secs)
+    }
+    pub fn output_target(&self) -> OutputTarget {
+        match self.output_backend {
+            OutputBackend::Device => OutputTarget::Device(self.output_device.clone()),
+            OutputBackend::Null => OutputTarget::Null {
+                speed: self.null_speed,
+            },
+            OutputBackend::Wav => OutputTarget::Wav(
+                self.wav_path
+                    .clone()
+                    .unwrap_or_else(|| PathBuf::from("output.wav")),
+            ),
+        }
+    }
+    pub fn set_output_target(&mut self, target: OutputTarget) {
+        match target {
+            OutputTarget::Device(device) => {
+                self.output_backend = OutputBackend::Device;
+                self.output_device = device;
+            }
+            OutputTarget::Null { speed } => {
+                self.output_backend = OutputBackend::Null;
+                self.null_speed = speed;
+            }
+            OutputTarget::Wav(path) => {
+                self.output_backend = OutputBackend::Wav;
+                self.wav_path = Some(path);
+            }
+        }
+    }
+}
+
+async fn get_config_path() -> Result<PathBuf, std::io::Error> {
+    Ok(get_default_path().await?.join("config.json"))
+}
+
+///Null backend speed that playback can be timed with
+pub fn valid_speed(speed: f32) -> bool {
+    speed.is_finite() && speed > 0.0
+}
@@ -0,0 +1,42 @@
+use std::future::Future;
+
+use tokio::{sync::mpsc::UnboundedSender, task::JoinHandle};
+
+tokio::task_local! {
+    ///Set while daemon runs command of client, which gets printed lines instead of stdout
+    static OUTPUT: UnboundedSender<String>;
+}
+
+///`println!` that goes to client of daemon when there's one
+macro_rules! say {
+    ($($arg:tt)*) => {
+        $crate::console::say(format!($($arg)*))
+    };
+}
+
+pub fn say(line: String) {
+    let sent = OUTPUT
+        .try_with(|output| output.send(line.clone()).is_ok())
+        .unwrap_or(false);
+    if !sent {
+        println!("{line}");
+    }
+}
+
+///Runs future with its `say!` lines sent to `output`
+pub async fn capture<F: Future>(output: UnboundedSender<String>, f: F) -> F::Output {
+    OUTPUT.scope(output, f).await
+}
+
+///`tokio::spawn` whose task sends its `say!` lines where caller's go, as task-local
+///isn't inherited by spawned tasks
+pub fn spawn<F>(f: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    match OUTPUT.try_with(UnboundedSender::clone) {
+        Ok(output) => tokio::spawn(capture(output, f)),
+        Err(_) => tokio::spawn(f),
+    }
+}
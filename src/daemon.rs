@@ -0,0 +1,537 @@
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+use reedline::ExternalPrinter;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs,
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream, unix::OwnedWriteHalf},
+    sync::{
+        mpsc::{self, Receiver, Sender, UnboundedReceiver},
+        watch,
+    },
+    task::JoinHandle,
+};
+
+use crate::{
+    App, Commands, ONE_SHOT_POLL, Outcome, apply_key_action,
+    config::Config,
+    console,
+    http::{self, ApiRequest},
+    keybindings::KeyAction,
+    mpd::{self, Mpd, MpdRequest},
+    parse_args,
+    playlist::NowPlayingInfo,
+    print_pending, run_command,
+    utils::get_default_path,
+};
+
+///Client which doesn't send its request in time gets answered with failure
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+///First line client sends
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Request {
+    ///Words of command same as typed in REPL
+    Command { args: Vec<String> },
+    ///Playback key pressed in REPL
+    Key { key: KeyAction },
+    ///Keeps connection open, getting `player` line on every change
+    Follow { follow: Topic },
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Topic {
+    Player,
+}
+
+///Lines daemon answers with, `done` is the last one
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Response {
+    Output {
+        line: String,
+    },
+    Done {
+        ok: bool,
+    },
+    ///Track and pause state, sent to followers of player
+    Player {
+        now_playing: Option<NowPlayingInfo>,
+    },
+}
+
+pub async fn socket_path() -> Result<PathBuf, std::io::Error> {
+    Ok(get_default_path().await?.join("yta.sock"))
+}
+
+///Whether command can run in daemon, `tui` needs terminal and `source` reads file of client
+pub fn forwards(command: &Commands) -> bool {
+    !matches!(
+        command,
+        Commands::Daemon | Commands::Tui | Commands::Source { .. }
+    )
+}
+
+async fn connect() -> Option<UnixStream> {
+    UnixStream::connect(socket_path().await.ok()?).await.ok()
+}
+
+pub async fn is_running() -> bool {
+    connect().await.is_some()
+}
+
+///Runs command in daemon and prints its output, `None` when no daemon is running
+pub async fn send_command(args: Vec<String>) -> Option<bool> {
+    send_request(Request::Command { args }).await
+}
+
+///Presses playback key in daemon, `None` when no daemon is running
+pub async fn send_key(key: KeyAction) -> Option<bool> {
+    send_request(Request::Key { key }).await
+}
+
+async fn send_request(request: Request) -> Option<bool> {
+    let stream = connect().await?;
+    Some(match exchange(stream, request).await {
+        Ok(ok) => ok,
+        Err(e) => {
+            say!("Lost connection to daemon: {e}");
+            false
+        }
+    })
+}
+
+async fn exchange(stream: UnixStream, request: Request) -> Result<bool, std::io::Error> {
+    let (read, mut write) = stream.into_split();
+    let mut line = serde_json::to_string(&request)?;
+    line.push('\n');
+    write.write_all(line.as_bytes()).await?;
+
+    let mut lines = BufReader::new(read).lines();
+    while let Some(line) = lines.next_line().await? {
+        match serde_json::from_str(&line)? {
+            Response::Output { line } => say!("{line}"),
+            Response::Done { ok } => return Ok(ok),
+            Response::Player { .. } => {}
+        }
+    }
+    Err(std::io::ErrorKind::UnexpectedEof.into())
+}
+
+///Mirrors daemon's player into `player`, so REPL client's prompt shows its track. Ends
+///together with daemon, leaving nothing playing
+pub async fn follow_player(player: watch::Sender<Option<NowPlayingInfo>>) {
+    let _ = mirror_player(&player).await;
+    player.send_replace(None);
+}
+
+async fn mirror_player(
+    player: &watch::Sender<Option<NowPlayingInfo>>,
+) -> Result<(), std::io::Error> {
+    let stream = connect().await.ok_or(std::io::ErrorKind::NotConnected)?;
+    let (read, mut write) = stream.into_split();
+    let mut line = serde_json::to_string(&Request::Follow {
+        follow: Topic::Player,
+    })?;
+    line.push('\n');
+    write.write_all(line.as_bytes()).await?;
+
+    let mut lines = BufReader::new(read).lines();
+    while let Some(line) = lines.next_line().await? {
+        if let Response::Player { now_playing } = serde_json::from_str(&line)? {
+            player.send_replace(now_playing);
+        }
+    }
+    Ok(())
+}
+
+///Serves commands sent to socket until client sends `exit`
+pub async fn serve(app: &mut App) -> Outcome {
+    let path = match socket_path().await {
+        Ok(v) => v,
+        Err(e) => {
+            say!("Failed to get socket path: {e}");
+            return Outcome::Failed;
+        }
+    };
+    if is_running().await {
+        say!("Daemon is already running at {}", path.display());
+        return Outcome::Failed;
+    }
+    //Left behind by daemon which didn't exit cleanly
+    let _ = fs::remove_file(&path).await;
+    let listener = match UnixListener::bind(&path) {
+        Ok(v) => v,
+        Err(e) => {
+            say!("Failed to listen on {}: {e}", path.display());
+            return Outcome::Failed;
+        }
+    };
+    say!("Listening on {}", path.display());
+    let (mut mpd_requests, mut api_requests) = match Config::load().await {
+        Ok(mut config) => (start_mpd(&config).await, start_http(&mut config).await),
+        Err(e) => {
+            say!("Failed to load config, MPD and HTTP servers stay off: {e}");
+            (None, None)
+        }
+    };
+    let mut clients = accept_clients(listener);
+    let mut mpd = Mpd::default();
+    //Messages of playback started by MPD clients, nobody else reads them
+    let mut printer_tick = tokio::time::interval(ONE_SHOT_POLL);
+
+    loop {
+        tokio::select! {
+            Some(client) = clients.recv() => {
+                let (outcome, response) = handle_client(client, app).await;
+                if outcome == Outcome::Exit {
+                    let _ = response.await;
+                    break;
+                }
+            }
+            Some(request) = next_request(&mut mpd_requests) => {
+                mpd.answer(request, app).await;
+            }
+            Some(request) = next_request(&mut api_requests) => http::answer(request, app).await,
+            _ = printer_tick.tick() => print_pending(&app.printer),
+        }
+    }
+    let _ = fs::remove_file(&path).await;
+    app.stop_playback();
+    Outcome::Done
+}
+
+async fn start_mpd(config: &Config) -> Option<Receiver<MpdRequest>> {
+    let port = config.mpd_port?;
+    match mpd::listen(port).await {
+        Ok(requests) => {
+            say!("MPD clients can connect to 127.0.0.1:{port}");
+            Some(requests)
+        }
+        Err(e) => {
+            say!("Failed to listen for MPD clients on port {port}: {e}");
+            None
+        }
+    }
+}
+
+async fn start_http(config: &mut Config) -> Option<Receiver<ApiRequest>> {
+    let port = config.http_port?;
+    let address = config.http_address;
+    let token = if address.is_loopback() {
+        config.http_token.clone()
+    } else {
+        match http::ensure_token(config).await {
+            Ok(token) => Some(token),
+            Err(e) => {
+                say!("Failed to create HTTP token: {e}");
+                return None;
+            }
+        }
+    };
+    match http::listen(address, port, token.clone()).await {
+        Ok(requests) => {
+            let host = SocketAddr::new(address, port);
+            match token {
+                Some(token) if !address.is_loopback() => {
+                    say!("Web remote at http://{host}/?token={token}")
+                }
+                _ => say!("Web remote at http://{host}/"),
+            }
+            Some(requests)
+        }
+        Err(e) => {
+            say!("Failed to start HTTP server on {address}:{port}: {e}");
+            None
+        }
+    }
+}
+
+///Request line of client, read by task of its own so slow clients don't hold up others
+struct ClientRequest {
+    request: Result<Request, String>,
+    write: OwnedWriteHalf,
+}
+
+fn accept_clients(listener: UnixListener) -> Receiver<ClientRequest> {
+    let (tx, rx) = mpsc::channel(16);
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => {
+                        tokio::spawn(read_request(stream, tx.clone()));
+                    }
+                    Err(e) => say!("Failed to accept client: {e}"),
+                },
+                _ = tx.closed() => break,
+            }
+        }
+    });
+    rx
+}
+
+async fn read_request(stream: UnixStream, requests: Sender<ClientRequest>) {
+    let (read, write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    let request = match tokio::time::timeout(REQUEST_TIMEOUT, lines.next_line()).await {
+        Ok(Ok(Some(line))) => {
+            serde_json::from_str(&line).map_err(|e| format!("Invalid request: {e}"))
+        }
+        Ok(Ok(None)) => Err("No request received".to_string()),
+        Ok(Err(e)) => Err(format!("Failed to read request: {e}")),
+        Err(_) => Err("No request received in time".to_string()),
+    };
+    let _ = requests.send(ClientRequest { request, write }).await;
+}
+
+///Waits for next request of server, never resolves when it's off
+async fn next_request<T>(requests: &mut Option<Receiver<T>>) -> Option<T> {
+    match requests {
+        Some(requests) => requests.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+///Output of command run for client, downloads it started may still be running
+pub struct CommandRun {
+    pub outcome: Outcome,
+    pub output: UnboundedReceiver<String>,
+    ///Receives messages of downloads
+    pub printer: ExternalPrinter<String>,
+    pub tasks: Vec<JoinHandle<bool>>,
+}
+
+impl CommandRun {
+    ///Lines printed since last call, by command first and then by its downloads
+    pub fn take_lines(&mut self) -> Vec<String> {
+        let mut lines: Vec<String> = std::iter::from_fn(|| self.output.try_recv().ok()).collect();
+        lines.extend(std::iter::from_fn(|| self.printer.get_line()));
+        lines
+    }
+    pub fn finished(&self) -> bool {
+        self.tasks.iter().all(|task| task.is_finished())
+    }
+    ///Waits for downloads, returns whether they and command succeeded
+    pub async fn success(self) -> bool {
+        let mut success = self.outcome != Outcome::Failed;
+        for task in self.tasks {
+            success &= task.await.unwrap_or(false);
+        }
+        success
+    }
+}
+
+///Runs words of command with its output captured, instead of printed by daemon
+pub async fn run_captured(args: &[String], app: &mut App) -> CommandRun {
+    let (output, output_rx) = mpsc::unbounded_channel();
+    let printer = ExternalPrinter::default();
+    let earlier_printer = std::mem::replace(&mut app.printer, printer.clone());
+    let run = async {
+        match parse_args(args) {
+            //Boxed as `daemon` itself runs from `run_command`
+            Ok(command) if forwards(&command) => Box::pin(run_command(command, app)).await,
+            Ok(_) => {
+                say!("Command can't run in daemon");
+                Outcome::Failed
+            }
+            Err(outcome) => outcome,
+        }
+    };
+    let outcome = console::capture(output, run).await;
+    app.printer = earlier_printer;
+    CommandRun {
+        outcome,
+        output: output_rx,
+        printer,
+        tasks: std::mem::take(&mut app.tasks),
+    }
+}
+
+///Runs command of client, its downloads get waited for by returned task answering client
+async fn handle_client(client: ClientRequest, app: &mut App) -> (Outcome, JoinHandle<()>) {
+    let ClientRequest { request, write } = client;
+    let run = match request {
+        Ok(Request::Command { args }) => run_captured(&args, app).await,
+        Ok(Request::Key { key }) => {
+            let seek_step = Config::load().await.unwrap_or_default().seek_step_secs;
+            let (output, output_rx) = mpsc::unbounded_channel();
+            let outcome = match apply_key_action(key, seek_step, app) {
+                Ok(()) => Outcome::Done,
+                Err(e) => {
+                    let _ = output.send(e);
+                    Outcome::Failed
+                }
+            };
+            finished_run(outcome, output_rx)
+        }
+        Ok(Request::Follow {
+            follow: Topic::Player,
+        }) => {
+            let follower = tokio::spawn(send_player(write, app.player.subscribe()));
+            return (Outcome::Done, follower);
+        }
+        Err(e) => {
+            let (output, output_rx) = mpsc::unbounded_channel();
+            let _ = output.send(e);
+            finished_run(Outcome::Failed, output_rx)
+        }
+    };
+    let outcome = run.outcome;
+    let response = tokio::spawn(respond(write, run));
+    (outcome, response)
+}
+
+///Run of request which printed everything already and started nothing
+fn finished_run(outcome: Outcome, output: UnboundedReceiver<String>) -> CommandRun {
+    CommandRun {
+        outcome,
+        output,
+        printer: ExternalPrinter::default(),
+        tasks: Vec::new(),
+    }
+}
+
+///Sends output of command, then messages of downloads it started until they finish
+async fn respond(mut write: OwnedWriteHalf, mut run: CommandRun) {
+    let mut lines = run.take_lines();
+    let mut connected = send_lines(&mut write, &mut lines).await;
+
+    while !run.finished() {
+        lines.extend(run.take_lines());
+        connected &= send_lines(&mut write, &mut lines).await;
+        tokio::time::sleep(ONE_SHOT_POLL).await;
+    }
+    lines.extend(run.take_lines());
+    let success = run.success().await;
+    if connected && send_lines(&mut write, &mut lines).await {
+        let _ = send(&mut write, &Response::Done { ok: success }).await;
+    }
+}
+
+///Sends player's state now and after every change, until client is gone
+async fn send_player(
+    mut write: OwnedWriteHalf,
+    mut player: watch::Receiver<Option<NowPlayingInfo>>,
+) {
+    loop {
+        let now_playing = player.borrow_and_update().clone();
+        if send(&mut write, &Response::Player { now_playing })
+            .await
+            .is_err()
+            || player.changed().await.is_err()
+        {
+            return;
+        }
+    }
+}
+
+///Returns false once client is gone, lines are dropped then
+async fn send_lines(write: &mut OwnedWriteHalf, lines: &mut Vec<String>) -> bool {
+    for line in lines.drain(..) {
+        if send(write, &Response::Output { line }).await.is_err() {
+            return false;
+        }
+    }
+    true
+}
+
+async fn send(write: &mut OwnedWriteHalf, response: &Response) -> Result<(), std::io::Error> {
+    let mut line = serde_json::to_string(response)?;
+    line.push('\n');
+    write.write_all(line.as_bytes()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///Parses line of README and checks it serializes back to the same JSON
+    fn round_trip<T>(line: &str) -> T
+    where
+        T: Serialize + for<'de> Deserialize<'de>,
+    {
+        let value: T = serde_json::from_str(line).unwrap();
+        let expected: serde_json::Value = serde_json::from_str(line).unwrap();
+        assert_eq!(serde_json::to_value(&value).unwrap(), expected);
+        value
+    }
+
+    #[test]
+    fn requests_match_readme() {
+        assert_eq!(
+            round_trip::<Request>(r#"{"args": ["play", "mix", "--track", "2"]}"#),
+            Request::Command {
+                args: vec!["play".into(), "mix".into(), "--track".into(), "2".into()]
+            }
+        );
+        assert_eq!(
+            round_trip::<Request>(r#"{"key": "play-pause"}"#),
+            Request::Key {
+                key: KeyAction::PlayPause
+            }
+        );
+        assert_eq!(
+            round_trip::<Request>(r#"{"follow": "player"}"#),
+            Request::Follow {
+                follow: Topic::Player
+            }
+        );
+        assert!(serde_json::from_str::<Request>(r#"{"follow": "queue"}"#).is_err());
+    }
+
+    #[test]
+    fn responses_match_readme() {
+        assert_eq!(
+            round_trip::<Response>(r#"{"type": "output", "line": "Downloaded: song.mp3"}"#),
+            Response::Output {
+                line: "Downloaded: song.mp3".into()
+            }
+        );
+        assert_eq!(
+            round_trip::<Response>(r#"{"type": "done", "ok": true}"#),
+            Response::Done { ok: true }
+        );
+        assert_eq!(
+            round_trip::<Response>(
+                r#"{"type": "player", "now_playing": {"playlist": "mix", "index": 0, "track": "Song", "paused": false}}"#
+            ),
+            Response::Player {
+                now_playing: Some(NowPlayingInfo {
+                    playlist: "mix".into(),
+                    index: 0,
+                    track: "Song".into(),
+                    paused: false,
+                })
+            }
+        );
+        round_trip::<Response>(r#"{"type": "player", "now_playing": null}"#);
+    }
+
+    #[tokio::test]
+    async fn silent_client_does_not_hold_up_others() {
+        let dir = std::env::temp_dir().join(format!("yta-cli-daemon-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("yta.sock");
+        let _ = std::fs::remove_file(&path);
+        let mut clients = accept_clients(UnixListener::bind(&path).unwrap());
+
+        let _silent = UnixStream::connect(&path).await.unwrap();
+        let mut talking = UnixStream::connect(&path).await.unwrap();
+        talking.write_all(b"{\"key\": \"next\"}\n").await.unwrap();
+        let client = tokio::time::timeout(Duration::from_secs(1), clients.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            client.request,
+            Ok(Request::Key {
+                key: KeyAction::Next
+            })
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
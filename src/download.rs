@@ -1,31 +1,60 @@
-use std::path::PathBuf;
+use std::{
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
 
-use tokio::{fs, process::Command};
+use tokio::fs;
 
 use crate::{
     error::Error,
     queue::{Queue, QueueItem},
-    utils::{Paths, get_playlists_dir, get_title_of_url},
+    search::Video,
+    utils::{Paths, get_playlist_dir, get_title_of_url, run_yt_dlp},
 };
 
+///Counts download commands still running, shown in REPL prompt
+#[derive(Clone, Default)]
+pub struct DownloadJobs(Arc<AtomicUsize>);
+impl DownloadJobs {
+    ///Job is counted until returned guard gets dropped
+    pub fn start(&self) -> DownloadJob {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        DownloadJob(Arc::clone(&self.0))
+    }
+    pub fn active(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+pub struct DownloadJob(Arc<AtomicUsize>);
+impl Drop for DownloadJob {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
 pub async fn download_youtube_video_audio(
     paths: Paths,
     url: &str,
     output_path: PathBuf,
-) -> Result<(), std::io::Error> {
-    let mut cmd = Command::new(paths.yt_dlp_path);
-    if let Some(path) = paths.ffmpeg_path {
-        cmd.arg("--ffmpeg-location").arg(path);
-    }
-    cmd.arg("--quiet")
-        .arg("-x")
-        .arg("--audio-format")
-        .arg("mp3")
-        .arg("-o")
-        .arg(output_path)
-        .arg(url);
-
-    cmd.status().await?;
+) -> Result<(), Error> {
+    run_yt_dlp(
+        &paths,
+        [
+            "--quiet".as_ref(),
+            "-x".as_ref(),
+            "--audio-format".as_ref(),
+            "mp3".as_ref(),
+            "-o".as_ref(),
+            output_path.as_os_str(),
+            "--".as_ref(),
+            url.as_ref(),
+        ],
+    )
+    .await?;
 
     Ok(())
 }
@@ -33,44 +62,54 @@ pub async fn download_youtube_playlist(
     paths: Paths,
     playlist_url: &str,
     playlist_name: &str,
-    queue: &mut Queue,
 ) -> Result<(), Error> {
-    let playlist_path = get_playlists_dir().await?.join(playlist_name);
+    let playlist_path = get_playlist_dir(playlist_name).await?;
 
     fs::create_dir_all(&playlist_path).await?;
 
-    let output = Command::new(&paths.yt_dlp_path)
-        .arg("--flat-playlist")
-        .arg("-J")
-        .arg(playlist_url)
-        .output()
-        .await?;
-
-    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stdout = run_yt_dlp(&paths, ["--flat-playlist", "-J", "--", playlist_url]).await?;
     let parsed: serde_json::Value = serde_json::from_str(&stdout)?;
 
     let entries = parsed["entries"]
         .as_array()
-        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::Other, "Invalid playlist JSON"))?;
+        .ok_or_else(|| std::io::Error::other("Invalid playlist JSON"))?;
 
     for entry in entries.iter() {
         if let Some(video_id) = entry["id"].as_str() {
             let paths = paths.clone();
             let url = format!("https://www.youtube.com/watch?v={}", video_id);
 
-            let title = get_title_of_url(paths.yt_dlp_path.clone(), &url).await?;
+            let title = get_title_of_url(&paths, &url).await?;
             let clean = sanitize_filename::sanitize(title.trim());
             let filename = format!("{}.mp3", clean);
             let output_path = playlist_path.join(&filename);
 
-            queue.items.push(QueueItem {
-                file_path: output_path.to_string_lossy().to_string(),
-                name: clean,
-            });
+            download_youtube_video_audio(paths, &url, output_path.clone()).await?;
 
-            download_youtube_video_audio(paths, &url, output_path).await?;
+            let mut item = QueueItem::new(output_path.to_string_lossy().to_string(), clean);
+            item.uploader = entry["channel"].as_str().map(String::from);
+            Queue::append_to_json(playlist_name, item).await?;
         }
     }
 
     Ok(())
 }
+
+///Downloads search result into playlist, named by title search already reported.
+///Returns file name
+pub async fn download_search_result(
+    paths: Paths,
+    video: &Video,
+    playlist_name: &str,
+) -> Result<String, Error> {
+    let clean = sanitize_filename::sanitize(video.title.trim());
+    let filename = format!("{}.mp3", clean);
+    let output_path = get_playlist_dir(playlist_name).await?.join(&filename);
+
+    download_youtube_video_audio(paths, &video.link(), output_path.clone()).await?;
+    let mut item = QueueItem::new(output_path.to_string_lossy().to_string(), clean);
+    item.uploader = video.channel.clone();
+    Queue::append_to_json(playlist_name, item).await?;
+
+    Ok(filename)
+}
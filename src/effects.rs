@@ -0,0 +1,389 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        Arc,
+        atomic::{AtomicU32, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use rodio::{ChannelCount, SampleRate, Source, source::SeekError};
+
+///How often `TrackedPosition` publishes position, in samples
+const POSITION_UPDATE_INTERVAL: u64 = 512;
+
+///f32 that can be changed from outside of audio thread
+#[derive(Clone)]
+pub struct SharedF32(Arc<AtomicU32>);
+impl SharedF32 {
+    pub fn new(value: f32) -> Self {
+        Self(Arc::new(AtomicU32::new(value.to_bits())))
+    }
+    pub fn get(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+    pub fn set(&self, value: f32) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+}
+
+///Position in decoded audio, unaffected by speed changes
+#[derive(Clone, Default)]
+pub struct PositionHandle(Arc<AtomicU64>);
+impl PositionHandle {
+    pub fn get(&self) -> Duration {
+        Duration::from_micros(self.0.load(Ordering::Relaxed))
+    }
+    fn set(&self, value: Duration) {
+        self.0.store(value.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+///Counts samples taken from decoder and publishes them as position
+pub struct TrackedPosition<S> {
+    input: S,
+    offset: Duration,
+    samples: u64,
+    position: PositionHandle,
+}
+impl<S: Source> TrackedPosition<S> {
+    pub fn new(input: S, position: PositionHandle) -> Self {
+        position.set(Duration::ZERO);
+        Self {
+            input,
+            offset: Duration::ZERO,
+            samples: 0,
+            position,
+        }
+    }
+    fn publish(&self) {
+        let per_second = self.input.sample_rate() as f64 * self.input.channels() as f64;
+        let played = Duration::from_secs_f64(self.samples as f64 / per_second);
+        self.position.set(self.offset + played);
+    }
+}
+impl<S: Source> Iterator for TrackedPosition<S> {
+    type Item = S::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let sample = self.input.next()?;
+        self.samples += 1;
+        if self.samples.is_multiple_of(POSITION_UPDATE_INTERVAL) {
+            self.publish();
+        }
+        Some(sample)
+    }
+}
+impl<S: Source> Source for TrackedPosition<S> {
+    fn current_span_len(&self) -> Option<usize> {
+        self.input.current_span_len()
+    }
+    fn channels(&self) -> ChannelCount {
+        self.input.channels()
+    }
+    fn sample_rate(&self) -> SampleRate {
+        self.input.sample_rate()
+    }
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.input.try_seek(pos)?;
+        self.offset = pos;
+        self.samples = 0;
+        self.publish();
+        Ok(())
+    }
+}
+
+///Changes tempo without changing pitch by overlapping slices of input (WSOLA)
+pub struct TimeStretch<S> {
+    input: S,
+    factor: SharedF32,
+    channels: usize,
+    ///Output frames emitted per step
+    hop: usize,
+    ///Frames crossfaded between consecutive slices
+    overlap: usize,
+    ///How far slice start may move to find the best matching waveform
+    search: usize,
+    ///Interleaved input not consumed yet
+    buffer: VecDeque<f32>,
+    ///Natural continuation of previously emitted slice, used for crossfade
+    tail: Vec<f32>,
+    ///Fractional part of input position carried between steps
+    carry: f32,
+    output: VecDeque<f32>,
+    input_done: bool,
+    ///Factor read at start of frame being emitted, changes can't swap channels mid-frame
+    frame_factor: f32,
+    ///Samples of that frame emitted so far
+    frame_sample: usize,
+}
+impl<S: Source> TimeStretch<S> {
+    pub fn new(input: S, factor: SharedF32) -> Self {
+        let channels = input.channels() as usize;
+        let rate = input.sample_rate() as usize;
+        Self {
+            input,
+            factor,
+            channels,
+            hop: rate * 30 / 1000,
+            overlap: rate * 10 / 1000,
+            search: rate * 10 / 1000,
+            buffer: VecDeque::new(),
+            tail: Vec::new(),
+            carry: 0.0,
+            output: VecDeque::new(),
+            input_done: false,
+            frame_factor: 1.0,
+            frame_sample: 0,
+        }
+    }
+    fn frames(&self) -> usize {
+        self.buffer.len() / self.channels
+    }
+    fn fill(&mut self, frames: usize) {
+        while !self.input_done && self.frames() < frames {
+            match self.input.next() {
+                Some(v) => self.buffer.push_back(v),
+                None => self.input_done = true,
+            }
+        }
+    }
+    fn correlation(&self, start: usize) -> f32 {
+        let mut score = 0.0;
+        for (i, t) in self.tail.iter().enumerate() {
+            score += t * self.buffer[start * self.channels + i];
+        }
+        score
+    }
+    ///Emits one slice of output, returns false when there is nothing left
+    fn step(&mut self, factor: f32) -> bool {
+        let advance = self.hop as f32 * factor + self.carry;
+        let needed = self.search + self.hop + self.overlap;
+        self.fill(needed.max(advance as usize));
+        if self.frames() < needed {
+            self.output.extend(self.buffer.drain(..));
+            self.tail.clear();
+            return !self.output.is_empty();
+        }
+
+        let delta = if self.tail.is_empty() {
+            0
+        } else {
+            (0..self.search)
+                .map(|delta| (delta, self.correlation(delta)))
+                .max_by(|a, b| a.1.total_cmp(&b.1))
+                .map_or(0, |(delta, _)| delta)
+        };
+
+        let ch = self.channels;
+        let start = delta * ch;
+        for i in 0..self.overlap * ch {
+            let sample = self.buffer[start + i];
+            let value = match self.tail.get(i) {
+                Some(t) => {
+                    let fade = (i / ch) as f32 / self.overlap as f32;
+                    t * (1.0 - fade) + sample * fade
+                }
+                None => sample,
+            };
+            self.output.push_back(value);
+        }
+        for i in self.overlap * ch..self.hop * ch {
+            self.output.push_back(self.buffer[start + i]);
+        }
+        self.tail.clear();
+        let tail_start = start + self.hop * ch;
+        self.tail.extend(
+            self.buffer
+                .range(tail_start..tail_start + self.overlap * ch),
+        );
+
+        let consumed = (advance as usize).min(self.frames());
+        self.carry = advance - consumed as f32;
+        self.buffer.drain(..consumed * ch);
+        true
+    }
+    fn reset(&mut self) {
+        self.buffer.clear();
+        self.tail.clear();
+        self.output.clear();
+        self.carry = 0.0;
+        self.input_done = false;
+        self.frame_sample = 0;
+    }
+    fn next_sample(&mut self, factor: f32) -> Option<f32> {
+        if let Some(v) = self.output.pop_front() {
+            return Some(v);
+        }
+        if (factor - 1.0).abs() < f32::EPSILON {
+            if self.buffer.is_empty() {
+                self.tail.clear();
+                return self.input.next();
+            }
+            return self.buffer.pop_front();
+        }
+        if self.step(factor) {
+            self.output.pop_front()
+        } else {
+            None
+        }
+    }
+}
+impl<S: Source> Iterator for TimeStretch<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.frame_sample == 0 {
+            self.frame_factor = self.factor.get();
+        }
+        let sample = self.next_sample(self.frame_factor)?;
+        self.frame_sample = (self.frame_sample + 1) % self.channels;
+        Some(sample)
+    }
+}
+impl<S: Source> Source for TimeStretch<S> {
+    fn current_span_len(&self) -> Option<usize> {
+        None
+    }
+    fn channels(&self) -> ChannelCount {
+        self.input.channels()
+    }
+    fn sample_rate(&self) -> SampleRate {
+        self.input.sample_rate()
+    }
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.input.try_seek(pos)?;
+        self.reset();
+        Ok(())
+    }
+}
+
+///Second order IIR filter in transposed direct form II
+#[derive(Clone, Copy, Default)]
+pub struct Biquad {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+    z1: f64,
+    z2: f64,
+}
+impl Biquad {
+    ///Coefficients normalized by a0
+    pub fn new(b: [f64; 3], a: [f64; 3]) -> Self {
+        Self {
+            b0: b[0] / a[0],
+            b1: b[1] / a[0],
+            b2: b[2] / a[0],
+            a1: a[1] / a[0],
+            a2: a[2] / a[0],
+            z1: 0.0,
+            z2: 0.0,
+        }
+    }
+    ///Replaces coefficients but keeps filter state, so changes don't click
+    pub fn set_coefficients(&mut self, other: &Biquad) {
+        self.b0 = other.b0;
+        self.b1 = other.b1;
+        self.b2 = other.b2;
+        self.a1 = other.a1;
+        self.a2 = other.a2;
+    }
+    ///Forgets previous samples, for jumps in audio
+    pub fn reset(&mut self) {
+        self.z1 = 0.0;
+        self.z2 = 0.0;
+    }
+    pub fn process(&mut self, x: f64) -> f64 {
+        let y = self.b0 * x + self.z1;
+        self.z1 = self.b1 * x - self.a1 * y + self.z2;
+        self.z2 = self.b2 * x - self.a2 * y;
+        y
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rodio::buffer::SamplesBuffer;
+
+    use super::*;
+
+    const RATE: u32 = 48000;
+
+    ///Stereo audio of `frames` frames, left channel at 0.5 and right at -0.5
+    fn stereo(frames: usize) -> SamplesBuffer {
+        SamplesBuffer::new(2, RATE, [0.5, -0.5].repeat(frames))
+    }
+
+    #[test]
+    fn stretched_length_follows_factor() {
+        for factor in [0.5, 0.75, 1.5, 2.0] {
+            let frames = RATE as usize * 2;
+            let out = TimeStretch::new(stereo(frames), SharedF32::new(factor)).count();
+            let expected = frames as f32 * 2.0 / factor;
+            assert!(
+                (out as f32 - expected).abs() / expected < 0.02,
+                "{factor}: {out}"
+            );
+        }
+    }
+
+    #[test]
+    fn speed_change_mid_frame_keeps_channels() {
+        let factor = SharedF32::new(1.0);
+        let mut stretch = TimeStretch::new(stereo(RATE as usize), factor.clone());
+        assert_eq!(stretch.next(), Some(0.5));
+        factor.set(1.5);
+        assert_eq!(stretch.next(), Some(-0.5));
+        let rest: Vec<f32> = stretch.by_ref().take(RATE as usize / 2).collect();
+        factor.set(1.0);
+        let after: Vec<f32> = stretch.collect();
+        for (i, v) in rest.iter().chain(&after).enumerate() {
+            let expected = if i % 2 == 0 { 0.5 } else { -0.5 };
+            assert!((v - expected).abs() < 1e-4, "sample {i} is {v}");
+        }
+    }
+
+    #[test]
+    fn position_follows_decoded_audio_at_other_speed() {
+        let position = PositionHandle::default();
+        let tracked = TrackedPosition::new(stereo(RATE as usize * 2), position.clone());
+        let mut stretch = TimeStretch::new(tracked, SharedF32::new(2.0));
+        //Half a second of output takes about a second of input
+        for _ in 0..RATE {
+            stretch.next();
+        }
+        let secs = position.get().as_secs_f64();
+        assert!((secs - 1.0).abs() < 0.1, "{secs}");
+
+        stretch.try_seek(Duration::from_millis(500)).unwrap();
+        assert_eq!(position.get(), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn biquad_follows_difference_equation() {
+        let mut average = Biquad::new([1.0, 1.0, 0.0], [2.0, 0.0, 0.0]);
+        let out: Vec<f64> = [1.0, 0.0, 0.0].map(|x| average.process(x)).to_vec();
+        assert_eq!(out, [0.5, 0.5, 0.0]);
+
+        let mut feedback = Biquad::new([1.0, 0.0, 0.0], [1.0, -0.5, 0.0]);
+        let out: Vec<f64> = [1.0, 0.0, 0.0].map(|x| feedback.process(x)).to_vec();
+        assert_eq!(out, [1.0, 0.5, 0.25]);
+    }
+
+    #[test]
+    fn new_coefficients_keep_filter_state() {
+        let mut filter = Biquad::new([1.0, 0.0, 0.0], [1.0, -0.5, 0.0]);
+        filter.process(1.0);
+        filter.set_coefficients(&Biquad::new([1.0, 0.0, 0.0], [1.0, -0.25, 0.0]));
+        assert_eq!(filter.process(0.0), 0.5);
+        assert_eq!(filter.process(0.0), 0.125);
+    }
+}
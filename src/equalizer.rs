@@ -0,0 +1,327 @@
+use std::{
+    f64::consts::PI,
+    fmt::{self, Display},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use rodio::{ChannelCount, SampleRate, Source, source::SeekError};
+use serde::{Deserialize, Serialize};
+
+use crate::effects::Biquad;
+
+pub const BANDS: [f64; 10] = [
+    31.0, 62.0, 125.0, 250.0, 500.0, 1000.0, 2000.0, 4000.0, 8000.0, 16000.0,
+];
+pub const PRESETS: [&str; 4] = ["flat", "bass-boost", "vocal", "podcast"];
+
+///Q giving each band about one octave of width
+const BAND_Q: f64 = 1.41;
+///How often `Equalizer` looks for changed settings, in samples
+const SETTINGS_CHECK_INTERVAL: usize = 1024;
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default)]
+pub struct EqSettings {
+    pub enabled: bool,
+    ///Gain in dB applied before bands, used to make room for boosts
+    pub preamp: f32,
+    ///Gain in dB of every band from `BANDS`
+    pub bands: [f32; 10],
+}
+impl Default for EqSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            preamp: 0.0,
+            bands: [0.0; 10],
+        }
+    }
+}
+impl EqSettings {
+    pub fn preset(name: &str) -> Option<Self> {
+        let (preamp, bands) = match name {
+            "flat" => (0.0, [0.0; 10]),
+            "bass-boost" => (-6.0, [6.0, 5.0, 4.0, 2.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]),
+            "vocal" => (-4.0, [-2.0, -2.0, -1.0, 1.0, 3.0, 4.0, 3.0, 1.0, 0.0, -1.0]),
+            "podcast" => (
+                -3.0,
+                [-6.0, -4.0, -1.0, 1.0, 2.0, 3.0, 3.0, 1.0, -1.0, -3.0],
+            ),
+            _ => return None,
+        };
+        Some(Self {
+            enabled: true,
+            preamp,
+            bands,
+        })
+    }
+    ///Whether gains are usable, NaN or infinite one would silence playback
+    pub fn is_finite(&self) -> bool {
+        self.preamp.is_finite() && self.bands.iter().all(|gain| gain.is_finite())
+    }
+}
+impl Display for EqSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = if self.enabled { "on" } else { "off" };
+        write!(f, "eq {state} | preamp {:+.1} dB", self.preamp)?;
+        for (i, gain) in self.bands.iter().enumerate() {
+            write!(f, " | {} {:+.1}", band_label(i), gain)?;
+        }
+        Ok(())
+    }
+}
+
+///Finds band by its number starting from 1 or its label like `125` or `1k`
+pub fn parse_band(band: &str) -> Option<usize> {
+    if let Some(i) = (0..BANDS.len()).find(|i| band_label(*i) == band) {
+        return Some(i);
+    }
+    match band.parse::<usize>() {
+        Ok(n) if (1..=BANDS.len()).contains(&n) => Some(n - 1),
+        _ => None,
+    }
+}
+
+fn band_label(i: usize) -> String {
+    let freq = BANDS[i];
+    if freq >= 1000.0 {
+        format!("{}k", freq / 1000.0)
+    } else {
+        format!("{freq}")
+    }
+}
+
+///Settings shared between REPL and every `Equalizer` created while playing
+#[derive(Clone)]
+pub struct EqHandle {
+    settings: Arc<Mutex<EqSettings>>,
+    version: Arc<AtomicU64>,
+}
+impl EqHandle {
+    pub fn new(settings: EqSettings) -> Self {
+        Self {
+            settings: Arc::new(Mutex::new(settings)),
+            version: Arc::new(AtomicU64::new(0)),
+        }
+    }
+    pub fn get(&self) -> EqSettings {
+        *self.settings.lock().unwrap()
+    }
+    pub fn set(&self, settings: EqSettings) {
+        *self.settings.lock().unwrap() = settings;
+        self.version.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+///Peaking filter from RBJ audio EQ cookbook
+fn peaking(freq: f64, gain_db: f32, rate: f64) -> Biquad {
+    let a = 10f64.powf(gain_db as f64 / 40.0);
+    let w0 = 2.0 * PI * freq / rate;
+    let alpha = w0.sin() / (2.0 * BAND_Q);
+    Biquad::new(
+        [1.0 + alpha * a, -2.0 * w0.cos(), 1.0 - alpha * a],
+        [1.0 + alpha / a, -2.0 * w0.cos(), 1.0 - alpha / a],
+    )
+}
+
+pub struct Equalizer<S> {
+    input: S,
+    handle: EqHandle,
+    version: u64,
+    settings: EqSettings,
+    preamp: f64,
+    ///Filters of every channel, bands above nyquist frequency are left out
+    filters: Vec<Vec<Biquad>>,
+    channel: usize,
+    since_check: usize,
+}
+impl<S: Source> Equalizer<S> {
+    pub fn new(input: S, handle: EqHandle) -> Self {
+        let channels = input.channels() as usize;
+        let mut eq = Self {
+            input,
+            version: handle.version.load(Ordering::Relaxed),
+            settings: handle.get(),
+            handle,
+            preamp: 1.0,
+            filters: vec![Vec::new(); channels],
+            channel: 0,
+            since_check: 0,
+        };
+        eq.update_filters();
+        eq
+    }
+    fn update_filters(&mut self) {
+        let rate = self.input.sample_rate() as f64;
+        self.preamp = 10f64.powf(self.settings.preamp as f64 / 20.0);
+        let designed: Vec<Biquad> = BANDS
+            .iter()
+            .zip(self.settings.bands)
+            .filter(|(freq, _)| **freq < rate * 0.45)
+            .map(|(freq, gain)| peaking(*freq, gain, rate))
+            .collect();
+        for filters in self.filters.iter_mut() {
+            if filters.len() != designed.len() {
+                *filters = designed.clone();
+            } else {
+                for (filter, new) in filters.iter_mut().zip(&designed) {
+                    filter.set_coefficients(new);
+                }
+            }
+        }
+    }
+}
+impl<S: Source> Iterator for Equalizer<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let sample = self.input.next()?;
+        self.since_check += 1;
+        if self.since_check >= SETTINGS_CHECK_INTERVAL {
+            self.since_check = 0;
+            let version = self.handle.version.load(Ordering::Relaxed);
+            if version != self.version {
+                self.version = version;
+                self.settings = self.handle.get();
+                self.update_filters();
+            }
+        }
+        let channel = self.channel;
+        self.channel = (self.channel + 1) % self.filters.len();
+
+        if !self.settings.enabled {
+            return Some(sample);
+        }
+        let mut value = sample as f64 * self.preamp;
+        for filter in self.filters[channel].iter_mut() {
+            value = filter.process(value);
+        }
+        Some(value as f32)
+    }
+}
+impl<S: Source> Source for Equalizer<S> {
+    fn current_span_len(&self) -> Option<usize> {
+        self.input.current_span_len()
+    }
+    fn channels(&self) -> ChannelCount {
+        self.input.channels()
+    }
+    fn sample_rate(&self) -> SampleRate {
+        self.input.sample_rate()
+    }
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.input.try_seek(pos)?;
+        //Audio continues from first channel, old state of filters would click
+        self.channel = 0;
+        self.filters.iter_mut().flatten().for_each(Biquad::reset);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: f64 = 48000.0;
+
+    ///Peak amplitude filter gives to sine of `freq`, once it settles
+    fn sine_gain(filter: &mut Biquad, freq: f64) -> f64 {
+        let samples = RATE as usize;
+        (0..samples)
+            .map(|i| filter.process((2.0 * PI * freq * i as f64 / RATE).sin()))
+            .skip(samples / 2)
+            .fold(0.0, |peak, v| v.abs().max(peak))
+    }
+
+    #[test]
+    fn peaking_boosts_center_frequency_by_gain() {
+        let mut filter = peaking(1000.0, 6.0, RATE);
+        let expected = 10f64.powf(6.0 / 20.0);
+        assert!((sine_gain(&mut filter, 1000.0) - expected).abs() < 0.01);
+        let mut filter = peaking(1000.0, -6.0, RATE);
+        assert!((sine_gain(&mut filter, 1000.0) - 1.0 / expected).abs() < 0.01);
+    }
+
+    #[test]
+    fn peaking_leaves_far_frequencies_alone() {
+        let mut filter = peaking(1000.0, 12.0, RATE);
+        assert!((sine_gain(&mut filter, 31.0) - 1.0).abs() < 0.05);
+        let mut filter = peaking(1000.0, 0.0, RATE);
+        assert!((sine_gain(&mut filter, 1000.0) - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn finds_bands_by_number_or_label() {
+        assert_eq!(parse_band("1"), Some(0));
+        assert_eq!(parse_band("125"), Some(2));
+        assert_eq!(parse_band("1k"), Some(5));
+        assert_eq!(parse_band("16k"), Some(9));
+        assert_eq!(parse_band("0"), None);
+        assert_eq!(parse_band("11"), None);
+    }
+
+    ///Stereo audio whose seeks land on first channel, like decoders' do
+    struct Frames {
+        data: Vec<f32>,
+        pos: usize,
+    }
+    impl Iterator for Frames {
+        type Item = f32;
+        fn next(&mut self) -> Option<f32> {
+            self.pos += 1;
+            self.data.get(self.pos - 1).copied()
+        }
+    }
+    impl Source for Frames {
+        fn current_span_len(&self) -> Option<usize> {
+            None
+        }
+        fn channels(&self) -> ChannelCount {
+            2
+        }
+        fn sample_rate(&self) -> SampleRate {
+            RATE as u32
+        }
+        fn total_duration(&self) -> Option<Duration> {
+            None
+        }
+        fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+            self.pos = (pos.as_secs_f64() * RATE) as usize * 2;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn seek_restarts_filters_from_first_channel() {
+        let settings = EqSettings::preset("bass-boost").unwrap();
+        let frames = || Frames {
+            data: [0.5, -0.5].repeat(4800),
+            pos: 0,
+        };
+        let mut eq = Equalizer::new(frames(), EqHandle::new(settings));
+        eq.by_ref().take(101).for_each(drop);
+        eq.try_seek(Duration::ZERO).unwrap();
+        let expected: Vec<f32> = Equalizer::new(frames(), EqHandle::new(settings)).collect();
+        assert_eq!(eq.collect::<Vec<f32>>(), expected);
+    }
+
+    #[test]
+    fn detects_non_finite_gains() {
+        assert!(EqSettings::preset("vocal").unwrap().is_finite());
+        let mut settings = EqSettings::default();
+        settings.bands[3] = f32::NAN;
+        assert!(!settings.is_finite());
+        let settings = EqSettings {
+            preamp: f32::INFINITY,
+            ..Default::default()
+        };
+        assert!(!settings.is_finite());
+    }
+}
@@ -1,15 +1,33 @@
+use crate::search::{SearchSource, SearchType};
 use std::{
     fmt::{self, Display},
+    path::PathBuf,
     string::FromUtf8Error,
 };
+#[derive(Debug)]
 pub enum Error {
-    Utf8Error(FromUtf8Error),
+    Utf8(FromUtf8Error),
     IO(std::io::Error),
     SerdeJson(serde_json::Error),
+    Stream(rodio::StreamError),
+    Devices(rodio::DevicesError),
+    DeviceNotFound(String),
+    Wav(hound::Error),
+    ///WAV file output is already writing to
+    WavInUse(PathBuf),
+    Reedline(reedline::ReedlineError),
+    ///yt-dlp exited unsuccessfully, holds its last error line
+    YtDlp(String),
+    ///Search provider can't look for this kind of results
+    UnsupportedSearch(SearchSource, SearchType),
+    ///Text that isn't a valid, finite and non-negative duration
+    InvalidDuration(String),
+    ///Search query without any words
+    EmptyQuery,
 }
 impl From<FromUtf8Error> for Error {
     fn from(value: FromUtf8Error) -> Self {
-        Self::Utf8Error(value)
+        Self::Utf8(value)
     }
 }
 
@@ -24,12 +42,49 @@ impl From<serde_json::Error> for Error {
         Self::SerdeJson(value)
     }
 }
+
+impl From<rodio::StreamError> for Error {
+    fn from(value: rodio::StreamError) -> Self {
+        Self::Stream(value)
+    }
+}
+
+impl From<rodio::DevicesError> for Error {
+    fn from(value: rodio::DevicesError) -> Self {
+        Self::Devices(value)
+    }
+}
+
+impl From<hound::Error> for Error {
+    fn from(value: hound::Error) -> Self {
+        Self::Wav(value)
+    }
+}
+
+impl From<reedline::ReedlineError> for Error {
+    fn from(value: reedline::ReedlineError) -> Self {
+        Self::Reedline(value)
+    }
+}
 impl Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Error::Utf8Error(e) => write!(f, "UTF-8 conversion error: {}", e),
+            Error::Utf8(e) => write!(f, "UTF-8 conversion error: {}", e),
             Error::IO(e) => write!(f, "I/O error: {}", e),
             Error::SerdeJson(e) => write!(f, "serde_json error: {}", e),
+            Error::Stream(e) => write!(f, "audio stream error: {}", e),
+            Error::Devices(e) => write!(f, "audio devices error: {}", e),
+            Error::DeviceNotFound(name) => write!(f, "output device not found: {}", name),
+            Error::Wav(e) => write!(f, "WAV error: {}", e),
+            Error::WavInUse(path) => write!(f, "already writing to {}", path.display()),
+            Error::Reedline(e) => write!(f, "line editor error: {}", e),
+            Error::YtDlp(e) => write!(f, "yt-dlp failed: {}", e),
+            Error::UnsupportedSearch(source, kind) => {
+                write!(f, "{} search can't find {}s", source, kind)
+            }
+            Error::InvalidDuration(input) => write!(f, "invalid duration: {}", input),
+            Error::EmptyQuery => write!(f, "search query is empty"),
         }
     }
 }
+impl std::error::Error for Error {}
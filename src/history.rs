@@ -0,0 +1,173 @@
+use std::{
+    fmt::{self, Display},
+    path::PathBuf,
+};
+
+use chrono::{Local, TimeZone};
+use serde::{Deserialize, Serialize};
+use tokio::{fs, io::AsyncReadExt, io::AsyncWriteExt};
+
+use crate::{
+    error::Error,
+    search::{SearchSession, SearchSource, SearchType, Video},
+    utils::get_default_path,
+};
+
+///Oldest searches get dropped once history is longer
+const HISTORY_LIMIT: usize = 100;
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct HistoryResult {
+    pub title: String,
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+impl HistoryResult {
+    pub fn to_video(&self) -> Video {
+        Video {
+            title: self.title.clone(),
+            id: self.id.clone(),
+            duration: None,
+            channel: None,
+            view_count: None,
+            upload_date: None,
+            url: self.url.clone(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct HistoryEntry {
+    pub query: String,
+    pub source: SearchSource,
+    pub kind: SearchType,
+    ///Unix time in milliseconds, also identifies the search
+    pub timestamp: i64,
+    pub results: Vec<HistoryResult>,
+}
+impl Display for HistoryEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let time = Local
+            .timestamp_millis_opt(self.timestamp)
+            .single()
+            .map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_default();
+        write!(
+            f,
+            "{time}  {} {}s  \"{}\" ({} results)",
+            self.source,
+            self.kind,
+            self.query,
+            self.results.len()
+        )
+    }
+}
+
+///Searches made so far, oldest first
+#[derive(Deserialize, Serialize, Default)]
+pub struct SearchHistory {
+    pub entries: Vec<HistoryEntry>,
+}
+impl SearchHistory {
+    pub async fn load() -> Result<Self, Error> {
+        let mut file = match fs::File::open(get_history_path().await?).await {
+            Ok(v) => v,
+            Err(_) => return Ok(Self::default()),
+        };
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf).await?;
+        Ok(serde_json::from_slice(&buf)?)
+    }
+    pub async fn save(&self) -> Result<(), Error> {
+        let mut file = fs::File::create(get_history_path().await?).await?;
+        file.write_all(&serde_json::to_vec_pretty(self)?).await?;
+        //One-shot `search` exits right after, before background write would finish
+        file.flush().await?;
+        Ok(())
+    }
+    ///Entry by number shown in `history`, 1 is the most recent search
+    pub fn get(&self, number: usize) -> Option<&HistoryEntry> {
+        self.entries.iter().rev().nth(number.checked_sub(1)?)
+    }
+    ///Saves results of session, replacing what was saved for it before
+    pub async fn record(session: &SearchSession) -> Result<(), Error> {
+        let mut history = Self::load().await?;
+        let entry = HistoryEntry {
+            query: session.query.clone(),
+            source: session.source,
+            kind: session.kind,
+            timestamp: session.started,
+            results: session
+                .results()
+                .map(|video| HistoryResult {
+                    title: video.title.clone(),
+                    id: video.id.clone(),
+                    url: video.url.clone(),
+                })
+                .collect(),
+        };
+        history.add(entry);
+        history.save().await
+    }
+    ///Adds entry as newest, unless it's the same search as newest one which it replaces
+    fn add(&mut self, entry: HistoryEntry) {
+        match self.entries.last_mut() {
+            Some(last) if last.timestamp == entry.timestamp => *last = entry,
+            _ => self.entries.push(entry),
+        }
+        let excess = self.entries.len().saturating_sub(HISTORY_LIMIT);
+        self.entries.drain(..excess);
+    }
+}
+
+async fn get_history_path() -> Result<PathBuf, std::io::Error> {
+    Ok(get_default_path().await?.join("search_history.json"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(timestamp: i64, results: usize) -> HistoryEntry {
+        HistoryEntry {
+            query: format!("query {timestamp}"),
+            source: SearchSource::Youtube,
+            kind: SearchType::Video,
+            timestamp,
+            results: (0..results)
+                .map(|i| HistoryResult {
+                    title: format!("title {i}"),
+                    id: format!("id{i}"),
+                    url: None,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn same_search_replaces_its_entry() {
+        let mut history = SearchHistory::default();
+        history.add(entry(1, 5));
+        history.add(entry(2, 5));
+        history.add(entry(2, 10));
+        assert_eq!(history.entries.len(), 2);
+        assert_eq!(history.get(1).map(|e| e.results.len()), Some(10));
+        assert_eq!(history.get(2).map(|e| e.timestamp), Some(1));
+        assert!(history.get(0).is_none() && history.get(3).is_none());
+    }
+
+    #[test]
+    fn drops_oldest_entries_past_limit() {
+        let mut history = SearchHistory::default();
+        for timestamp in 0..HISTORY_LIMIT as i64 + 5 {
+            history.add(entry(timestamp, 1));
+        }
+        assert_eq!(history.entries.len(), HISTORY_LIMIT);
+        assert_eq!(history.entries[0].timestamp, 5);
+        assert_eq!(
+            history.get(1).map(|e| e.timestamp),
+            Some(HISTORY_LIMIT as i64 + 4)
+        );
+    }
+}
@@ -0,0 +1,828 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    Json, Router,
+    extract::{ConnectInfo, Path, Query, Request, State},
+    http::{
+        StatusCode,
+        header::{AUTHORIZATION, HOST, ORIGIN},
+    },
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Response},
+    routing::{get, post},
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs,
+    io::AsyncReadExt,
+    net::TcpListener,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        oneshot,
+    },
+};
+
+use crate::{
+    App, ONE_SHOT_POLL,
+    config::Config,
+    daemon::{CommandRun, run_captured},
+    playlist::{PlaybackStatus, PlaylistControl},
+    queue::{Queue, QueueItem},
+    search::Video,
+    utils::{check_playlist_name, get_playlists},
+};
+
+const REMOTE_PAGE: &str = include_str!("remote.html");
+///Finished download jobs over this many get forgotten, oldest first
+const MAX_JOBS: usize = 50;
+const SEARCH_RESULTS: u16 = 10;
+
+///Work needing playback or running commands, done by daemon which owns them
+pub enum ApiRequest {
+    ///Playback and number of active downloads
+    Status(oneshot::Sender<(Option<PlaybackStatus>, usize)>),
+    ///Replies whether anything was playing to control
+    Control(PlaylistControl, oneshot::Sender<bool>),
+    Stop(oneshot::Sender<bool>),
+    ///Words of command as typed in REPL
+    Command(Vec<String>, oneshot::Sender<CommandRun>),
+    ///Current page of last search
+    SearchResults(oneshot::Sender<Vec<Video>>),
+}
+
+pub async fn answer(request: ApiRequest, app: &mut App) {
+    match request {
+        ApiRequest::Status(reply) => {
+            let _ = reply.send((app.playback_status().await, app.downloads.active()));
+        }
+        ApiRequest::Control(control, reply) => {
+            let _ = reply.send(app.control(control));
+        }
+        ApiRequest::Stop(reply) => {
+            let playing = app.playback_status().await.is_some();
+            app.stop_playback();
+            let _ = reply.send(playing);
+        }
+        ApiRequest::Command(args, reply) => {
+            let _ = reply.send(run_captured(&args, app).await);
+        }
+        ApiRequest::SearchResults(reply) => {
+            let last_search = app.last_search.lock().await;
+            let videos = last_search
+                .as_ref()
+                .and_then(|session| session.cached_page(session.page))
+                .cloned()
+                .unwrap_or_default();
+            let _ = reply.send(videos);
+        }
+    }
+}
+
+///Token clients from other machines have to send, generated and saved on first use
+pub async fn ensure_token(config: &mut Config) -> Result<String, std::io::Error> {
+    if let Some(token) = &config.http_token {
+        return Ok(token.clone());
+    }
+    let mut bytes = [0u8; 16];
+    fs::File::open("/dev/urandom")
+        .await?
+        .read_exact(&mut bytes)
+        .await?;
+    let token: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+    config.http_token = Some(token.clone());
+    config.save().await?;
+    Ok(token)
+}
+
+///Serves API and remote page until returned receiver is dropped. Clients other than
+///localhost need `token`
+pub async fn listen(
+    address: IpAddr,
+    port: u16,
+    token: Option<String>,
+) -> Result<Receiver<ApiRequest>, std::io::Error> {
+    let listener = TcpListener::bind((address, port)).await?;
+    let (tx, rx) = mpsc::channel(16);
+    let state = ApiState {
+        requests: tx.clone(),
+        token: token.map(Arc::from),
+        jobs: Jobs::default(),
+    };
+    let app = router(state).into_make_service_with_connect_info::<SocketAddr>();
+    tokio::spawn(async move {
+        let _ = axum::serve(listener, app)
+            .with_graceful_shutdown(async move { tx.closed().await })
+            .await;
+    });
+    Ok(rx)
+}
+
+#[derive(Clone)]
+struct ApiState {
+    requests: Sender<ApiRequest>,
+    token: Option<Arc<str>>,
+    jobs: Jobs,
+}
+
+fn router(state: ApiState) -> Router {
+    Router::new()
+        .route("/", get(|| async { Html(REMOTE_PAGE) }))
+        .route("/api/status", get(status))
+        .route("/api/player/{action}", post(player))
+        .route("/api/player/seek", post(seek))
+        .route("/api/player/volume", post(volume))
+        .route("/api/play", post(play))
+        .route("/api/playlists", get(playlists).post(create_playlist))
+        .route("/api/playlists/{name}", get(playlist))
+        .route(
+            "/api/playlists/{name}/tracks/{track}",
+            axum::routing::patch(rename_track).delete(remove_track),
+        )
+        .route(
+            "/api/playlists/{name}/tracks/{track}/enqueue",
+            post(enqueue_track),
+        )
+        .route("/api/search", get(search))
+        .route("/api/downloads", get(downloads).post(start_download))
+        .layer(middleware::from_fn_with_state(state.clone(), authorize))
+        .with_state(state)
+}
+
+///Lets localhost in, others need token as `Authorization: Bearer` header or `token` query.
+///Localhost also needs loopback `Host` and `Origin`, so other sites can't reach API through
+///DNS rebinding or cross-site requests from browser
+async fn authorize(
+    State(state): State<ApiState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let allowed =
+        state.token.as_deref().is_some_and(|token| {
+            request_token(&request).is_some_and(|given| same_token(given, token))
+        }) || (peer.ip().to_canonical().is_loopback() && from_loopback_site(&request));
+    if !allowed {
+        return ApiError(StatusCode::UNAUTHORIZED, "Missing or wrong token".into()).into_response();
+    }
+    next.run(request).await
+}
+
+///Compares in time not depending on where tokens differ
+fn same_token(given: &str, token: &str) -> bool {
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+///Whether `Host` names loopback address, and `Origin` does too when browser sends one
+fn from_loopback_site(request: &Request) -> bool {
+    let header = |name| {
+        request
+            .headers()
+            .get(name)
+            .map(|value| value.to_str().unwrap_or_default())
+    };
+    let host = header(HOST).is_some_and(is_loopback_host);
+    let origin = header(ORIGIN).is_none_or(|origin| {
+        origin
+            .split_once("://")
+            .is_some_and(|(_, host)| is_loopback_host(host))
+    });
+    host && origin
+}
+
+///Takes `host[:port]`, with IPv6 address in brackets
+fn is_loopback_host(host: &str) -> bool {
+    let name = match host.strip_prefix('[') {
+        Some(rest) => rest.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    };
+    name.eq_ignore_ascii_case("localhost")
+        || name
+            .parse::<IpAddr>()
+            .is_ok_and(|ip| ip.to_canonical().is_loopback())
+}
+
+fn request_token(request: &Request) -> Option<&str> {
+    let header = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    header.or_else(|| {
+        request
+            .uri()
+            .query()?
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("token="))
+    })
+}
+
+///Sent as `{"error": message}`
+struct ApiError(StatusCode, String);
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        #[derive(Serialize)]
+        struct Body {
+            error: String,
+        }
+        (self.0, Json(Body { error: self.1 })).into_response()
+    }
+}
+impl From<std::io::Error> for ApiError {
+    fn from(e: std::io::Error) -> Self {
+        ApiError(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    }
+}
+
+type ApiResult<T> = Result<Json<T>, ApiError>;
+
+///Playlist names from request bodies end up in paths, so they're checked before any use
+fn check_name(name: &str) -> Result<(), ApiError> {
+    check_playlist_name(name).map_err(|e| ApiError(StatusCode::BAD_REQUEST, e.to_string()))
+}
+
+fn nothing_playing() -> ApiError {
+    ApiError(
+        StatusCode::CONFLICT,
+        "Currently no playlist is playing".into(),
+    )
+}
+
+async fn ask<T>(
+    state: &ApiState,
+    request: impl FnOnce(oneshot::Sender<T>) -> ApiRequest,
+) -> Result<T, ApiError> {
+    let gone = || ApiError(StatusCode::SERVICE_UNAVAILABLE, "Daemon is exiting".into());
+    let (reply, rx) = oneshot::channel();
+    state
+        .requests
+        .send(request(reply))
+        .await
+        .map_err(|_| gone())?;
+    rx.await.map_err(|_| gone())
+}
+
+async fn control(state: &ApiState, control: PlaylistControl) -> ApiResult<Done> {
+    if ask(state, |reply| ApiRequest::Control(control, reply)).await? {
+        Ok(Json(Done { ok: true }))
+    } else {
+        Err(nothing_playing())
+    }
+}
+
+#[derive(Serialize)]
+struct Done {
+    ok: bool,
+}
+
+///Output of command, with messages of work it started in background once that's done
+#[derive(Serialize)]
+struct CommandOutput {
+    ok: bool,
+    output: Vec<String>,
+}
+
+async fn run_to_end(mut run: CommandRun) -> CommandOutput {
+    let mut output = Vec::new();
+    while !run.finished() {
+        output.extend(run.take_lines());
+        tokio::time::sleep(ONE_SHOT_POLL).await;
+    }
+    output.extend(run.take_lines());
+    CommandOutput {
+        ok: run.success().await,
+        output,
+    }
+}
+
+async fn command(state: &ApiState, args: Vec<String>) -> Result<CommandOutput, ApiError> {
+    let run = ask(state, |reply| ApiRequest::Command(args, reply)).await?;
+    Ok(run_to_end(run).await)
+}
+
+#[derive(Serialize)]
+struct StatusResponse {
+    playback: Option<Playback>,
+    downloads: usize,
+}
+
+#[derive(Serialize)]
+struct Playback {
+    playlist: String,
+    ///Counted from 1, like `--track` of `play`
+    track: usize,
+    title: String,
+    position: f64,
+    duration: Option<f64>,
+    paused: bool,
+    ///Percent
+    volume: u32,
+    speed: f32,
+}
+
+async fn status(State(state): State<ApiState>) -> ApiResult<StatusResponse> {
+    let (status, downloads) = ask(&state, ApiRequest::Status).await?;
+    let playback = status.map(|status| Playback {
+        track: status.index + 1,
+        title: status.track,
+        position: status.position.as_secs_f64(),
+        duration: status.duration.map(|v| v.as_secs_f64()),
+        paused: status.paused,
+        volume: (status.volume * 100.0).round() as u32,
+        speed: status.speed,
+        playlist: status.playlist,
+    });
+    Ok(Json(StatusResponse {
+        playback,
+        downloads,
+    }))
+}
+
+async fn player(State(state): State<ApiState>, Path(action): Path<String>) -> ApiResult<Done> {
+    let playback_control = match action.as_str() {
+        "toggle" => PlaylistControl::TogglePause,
+        "pause" => PlaylistControl::Pause,
+        "resume" => PlaylistControl::Play,
+        "next" => PlaylistControl::Skip,
+        "previous" => PlaylistControl::Previous,
+        "stop" => {
+            return if ask(&state, ApiRequest::Stop).await? {
+                Ok(Json(Done { ok: true }))
+            } else {
+                Err(nothing_playing())
+            };
+        }
+        _ => {
+            return Err(ApiError(
+                StatusCode::NOT_FOUND,
+                format!("Unknown action {action}"),
+            ));
+        }
+    };
+    control(&state, playback_control).await
+}
+
+///Either relative `by` seconds, negative going back, or absolute `to`
+#[derive(Deserialize)]
+struct SeekBody {
+    by: Option<i64>,
+    to: Option<f64>,
+}
+
+async fn seek(State(state): State<ApiState>, Json(body): Json<SeekBody>) -> ApiResult<Done> {
+    let playback_control = match (body.by, body.to) {
+        (Some(by), None) => PlaylistControl::SkipBy(by),
+        (None, Some(to)) => match std::time::Duration::try_from_secs_f64(to) {
+            Ok(to) => PlaylistControl::Seek(to),
+            Err(_) => return Err(ApiError(StatusCode::BAD_REQUEST, "Invalid `to`".into())),
+        },
+        _ => {
+            return Err(ApiError(
+                StatusCode::BAD_REQUEST,
+                "Give either `by` or `to`".into(),
+            ));
+        }
+    };
+    control(&state, playback_control).await
+}
+
+#[derive(Deserialize)]
+struct VolumeBody {
+    percent: u32,
+}
+
+async fn volume(State(state): State<ApiState>, Json(body): Json<VolumeBody>) -> ApiResult<Done> {
+    if body.percent > 100 {
+        return Err(ApiError(
+            StatusCode::BAD_REQUEST,
+            "Volume goes up to 100".into(),
+        ));
+    }
+    control(&state, PlaylistControl::Volume(body.percent as f32 / 100.0)).await
+}
+
+#[derive(Deserialize)]
+struct PlayBody {
+    playlist: String,
+    track: Option<usize>,
+}
+
+async fn play(
+    State(state): State<ApiState>,
+    Json(body): Json<PlayBody>,
+) -> ApiResult<CommandOutput> {
+    check_name(&body.playlist)?;
+    let mut args = vec!["play".to_string(), body.playlist];
+    if let Some(track) = body.track {
+        args.extend(["--track".to_string(), track.to_string()]);
+    }
+    Ok(Json(command(&state, args).await?))
+}
+
+async fn playlists() -> ApiResult<Vec<String>> {
+    Ok(Json(get_playlists().await?))
+}
+
+#[derive(Deserialize)]
+struct CreateBody {
+    name: String,
+}
+
+async fn create_playlist(
+    State(state): State<ApiState>,
+    Json(body): Json<CreateBody>,
+) -> ApiResult<CommandOutput> {
+    check_name(&body.name)?;
+    Ok(Json(
+        command(&state, vec!["create".to_string(), body.name]).await?,
+    ))
+}
+
+#[derive(Serialize)]
+struct Track {
+    ///Counted from 1
+    track: usize,
+    name: String,
+    uploader: Option<String>,
+}
+
+///Loads playlist without creating it, as loading missing one would
+async fn load_playlist(name: &str) -> Result<Vec<QueueItem>, ApiError> {
+    if !get_playlists()
+        .await?
+        .iter()
+        .any(|playlist| playlist == name)
+    {
+        return Err(ApiError(
+            StatusCode::NOT_FOUND,
+            format!("No playlist named {name}"),
+        ));
+    }
+    Ok(Queue::from_queue_json(name).await?.items)
+}
+
+async fn load_track(name: &str, track: usize) -> Result<QueueItem, ApiError> {
+    let items = load_playlist(name).await?;
+    track
+        .checked_sub(1)
+        .and_then(|i| items.into_iter().nth(i))
+        .ok_or_else(|| {
+            ApiError(
+                StatusCode::NOT_FOUND,
+                format!("Playlist {name} has no track {track}"),
+            )
+        })
+}
+
+async fn playlist(Path(name): Path<String>) -> ApiResult<Vec<Track>> {
+    let items = load_playlist(&name).await?;
+    Ok(Json(
+        items
+            .into_iter()
+            .enumerate()
+            .map(|(i, item)| Track {
+                track: i + 1,
+                name: item.name,
+                uploader: item.uploader,
+            })
+            .collect(),
+    ))
+}
+
+#[derive(Deserialize)]
+struct RenameBody {
+    name: String,
+}
+
+async fn rename_track(
+    Path((name, track)): Path<(String, usize)>,
+    Json(body): Json<RenameBody>,
+) -> ApiResult<Done> {
+    edit_track(&name, track, |items, i| items[i].name = body.name).await
+}
+
+async fn remove_track(Path((name, track)): Path<(String, usize)>) -> ApiResult<Done> {
+    edit_track(&name, track, |items, i| {
+        items.remove(i);
+    })
+    .await
+}
+
+///Edits track by its number while queue is locked, names of tracks don't have to be unique
+async fn edit_track(
+    name: &str,
+    track: usize,
+    edit: impl FnOnce(&mut Vec<QueueItem>, usize),
+) -> ApiResult<Done> {
+    load_playlist(name).await?;
+    let found = Queue::update(name, |queue| {
+        let index = track.checked_sub(1).filter(|i| *i < queue.items.len());
+        if let Some(index) = index {
+            edit(&mut queue.items, index);
+        }
+        Ok(index.is_some())
+    })
+    .await?;
+    if !found {
+        return Err(ApiError(
+            StatusCode::NOT_FOUND,
+            format!("Playlist {name} has no track {track}"),
+        ));
+    }
+    Ok(Json(Done { ok: true }))
+}
+
+async fn enqueue_track(
+    State(state): State<ApiState>,
+    Path((name, track)): Path<(String, usize)>,
+) -> ApiResult<Done> {
+    let item = load_track(&name, track).await?;
+    control(&state, PlaylistControl::Enqueue(item)).await
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    q: String,
+    results: Option<u16>,
+}
+
+#[derive(Serialize)]
+struct SearchResponse {
+    ///Numbered from 1 in order, download them by those numbers
+    results: Vec<Video>,
+    messages: Vec<String>,
+}
+
+///Fast flat search, results can be downloaded through `results` of `/api/downloads`
+async fn search(
+    State(state): State<ApiState>,
+    Query(query): Query<SearchQuery>,
+) -> ApiResult<SearchResponse> {
+    let args = vec![
+        "search".to_string(),
+        query.q,
+        "--number-of-results".to_string(),
+        query.results.unwrap_or(SEARCH_RESULTS).to_string(),
+        "--json".to_string(),
+        "--flat".to_string(),
+    ];
+    let output = command(&state, args).await?;
+    //Results come from search session itself, printed JSON is left out of messages
+    let results = if output.ok {
+        ask(&state, ApiRequest::SearchResults).await?
+    } else {
+        Vec::new()
+    };
+    let messages = output
+        .output
+        .into_iter()
+        .filter(|line| !line.starts_with('['))
+        .collect();
+    Ok(Json(SearchResponse { results, messages }))
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum JobState {
+    Running,
+    Done,
+    Failed,
+}
+
+#[derive(Serialize, Clone)]
+struct Job {
+    id: usize,
+    playlist: String,
+    ///URLs or search results being downloaded
+    source: String,
+    state: JobState,
+    messages: Vec<String>,
+}
+
+///Downloads started through API, newest last
+#[derive(Clone, Default)]
+struct Jobs(Arc<Mutex<(usize, Vec<Job>)>>);
+impl Jobs {
+    fn add(&self, playlist: String, source: String) -> Job {
+        let mut jobs = self.0.lock().unwrap();
+        jobs.0 += 1;
+        let job = Job {
+            id: jobs.0,
+            playlist,
+            source,
+            state: JobState::Running,
+            messages: Vec::new(),
+        };
+        jobs.1.push(job.clone());
+        while jobs.1.len() > MAX_JOBS {
+            match jobs.1.iter().position(|job| job.state != JobState::Running) {
+                Some(i) => jobs.1.remove(i),
+                None => break,
+            };
+        }
+        job
+    }
+    fn update(&self, id: usize, update: impl FnOnce(&mut Job)) {
+        if let Some(job) = self.0.lock().unwrap().1.iter_mut().find(|job| job.id == id) {
+            update(job);
+        }
+    }
+    fn list(&self) -> Vec<Job> {
+        self.0.lock().unwrap().1.clone()
+    }
+}
+
+///Collects messages of download until it's over
+async fn track_job(jobs: Jobs, id: usize, mut run: CommandRun) {
+    loop {
+        let finished = run.finished();
+        let lines = run.take_lines();
+        jobs.update(id, |job| job.messages.extend(lines));
+        if finished {
+            break;
+        }
+        tokio::time::sleep(ONE_SHOT_POLL).await;
+    }
+    let state = if run.success().await {
+        JobState::Done
+    } else {
+        JobState::Failed
+    };
+    jobs.update(id, |job| job.state = state);
+}
+
+async fn downloads(State(state): State<ApiState>) -> ApiResult<Vec<Job>> {
+    Ok(Json(state.jobs.list()))
+}
+
+///Either `urls` of videos, or `results` of last search like `2` or `1-3,5`
+#[derive(Deserialize)]
+struct DownloadBody {
+    playlist: String,
+    #[serde(default)]
+    urls: Vec<String>,
+    results: Option<String>,
+}
+
+async fn start_download(
+    State(state): State<ApiState>,
+    Json(body): Json<DownloadBody>,
+) -> Result<(StatusCode, Json<Job>), ApiError> {
+    check_name(&body.playlist)?;
+    //Leading dash would reach yt-dlp as an option
+    if body.urls.iter().any(|url| url.starts_with('-')) {
+        return Err(ApiError(
+            StatusCode::BAD_REQUEST,
+            "URLs can't start with `-`".into(),
+        ));
+    }
+    let (args, source) = match (body.urls.is_empty(), body.results) {
+        (false, None) => {
+            let mut args = vec!["download".to_string()];
+            args.extend(body.urls.iter().cloned());
+            args.extend(["--playlist-name".to_string(), body.playlist.clone()]);
+            (args, body.urls.join(" "))
+        }
+        (true, Some(results)) => (
+            vec![
+                "download-result".to_string(),
+                body.playlist.clone(),
+                "--result-index".to_string(),
+                results.clone(),
+            ],
+            format!("search results {results}"),
+        ),
+        _ => {
+            return Err(ApiError(
+                StatusCode::BAD_REQUEST,
+                "Give either `urls` or `results`".into(),
+            ));
+        }
+    };
+    let run = ask(&state, |reply| ApiRequest::Command(args, reply)).await?;
+    let job = state.jobs.add(body.playlist, source);
+    tokio::spawn(track_job(state.jobs.clone(), job.id, run));
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn request(uri: &str, headers: &[(&str, &str)]) -> Request {
+        let mut builder = Request::builder().uri(uri);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn recognizes_loopback_hosts() {
+        assert!(is_loopback_host("localhost"));
+        assert!(is_loopback_host("LocalHost:8080"));
+        assert!(is_loopback_host("127.0.0.1:8080"));
+        assert!(is_loopback_host("[::1]:8080"));
+        assert!(is_loopback_host("[::ffff:127.0.0.1]"));
+        assert!(!is_loopback_host("evil.com"));
+        assert!(!is_loopback_host("localhost.evil.com"));
+        assert!(!is_loopback_host("[::2]:8080"));
+        assert!(!is_loopback_host("192.168.1.2:8080"));
+    }
+
+    #[test]
+    fn loopback_site_needs_loopback_host_and_origin() {
+        assert!(from_loopback_site(&request(
+            "/",
+            &[("host", "127.0.0.1:6688")]
+        )));
+        assert!(from_loopback_site(&request(
+            "/",
+            &[
+                ("host", "localhost:6688"),
+                ("origin", "http://localhost:6688")
+            ]
+        )));
+        assert!(!from_loopback_site(&request("/", &[])));
+        assert!(!from_loopback_site(&request("/", &[("host", "evil.com")])));
+        assert!(!from_loopback_site(&request(
+            "/",
+            &[("host", "127.0.0.1:6688"), ("origin", "http://evil.com")]
+        )));
+        assert!(!from_loopback_site(&request(
+            "/",
+            &[("host", "127.0.0.1:6688"), ("origin", "null")]
+        )));
+    }
+
+    #[test]
+    fn compares_tokens() {
+        assert!(same_token("secret", "secret"));
+        assert!(!same_token("secreT", "secret"));
+        assert!(!same_token("secre", "secret"));
+        assert!(!same_token("", "secret"));
+        assert!(!same_token("secrets", "secret"));
+    }
+
+    #[test]
+    fn takes_token_from_header_or_query() {
+        let bearer = request("/", &[("authorization", "Bearer abc")]);
+        assert_eq!(request_token(&bearer), Some("abc"));
+        let query = request("/api/status?x=1&token=abc", &[]);
+        assert_eq!(request_token(&query), Some("abc"));
+        let basic = request("/", &[("authorization", "Basic abc")]);
+        assert_eq!(request_token(&basic), None);
+    }
+
+    async fn status_of(peer: [u8; 4], uri: &str, headers: &[(&str, &str)]) -> StatusCode {
+        let (requests, _rx) = mpsc::channel(1);
+        let state = ApiState {
+            requests,
+            token: Some(Arc::from("secret")),
+            jobs: Jobs::default(),
+        };
+        let mut request = request(uri, headers);
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from((peer, 40000))));
+        router(state).oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn router_checks_peer_site_and_token() {
+        let local = [127, 0, 0, 1];
+        let remote = [192, 168, 1, 2];
+        let host = ("host", "127.0.0.1:6688");
+        assert_eq!(status_of(local, "/", &[host]).await, StatusCode::OK);
+        assert_eq!(
+            status_of(local, "/", &[("host", "evil.com")]).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status_of(local, "/", &[host, ("origin", "http://evil.com")]).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status_of(remote, "/", &[host]).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status_of(remote, "/", &[("authorization", "Bearer wrong!")]).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status_of(remote, "/?token=secret", &[("host", "evil.com")]).await,
+            StatusCode::OK
+        );
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::BTreeMap;
+
+use reedline::{KeyCode, KeyModifiers, Keybindings, ReedlineEvent};
+use serde::{Deserialize, Serialize};
+
+use crate::playlist::PlaylistControl;
+
+///Starts line returned by reedline for bound key, can't come from typing
+const KEY_COMMAND_PREFIX: &str = "\0key:";
+
+///Playback action bound to key in config
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyAction {
+    PlayPause,
+    SeekForward,
+    SeekBackward,
+    Next,
+    Previous,
+}
+impl KeyAction {
+    const ALL: [KeyAction; 5] = [
+        KeyAction::PlayPause,
+        KeyAction::SeekForward,
+        KeyAction::SeekBackward,
+        KeyAction::Next,
+        KeyAction::Previous,
+    ];
+    fn name(self) -> &'static str {
+        match self {
+            KeyAction::PlayPause => "play-pause",
+            KeyAction::SeekForward => "seek-forward",
+            KeyAction::SeekBackward => "seek-backward",
+            KeyAction::Next => "next",
+            KeyAction::Previous => "previous",
+        }
+    }
+    pub fn control(self, seek_step_secs: u64) -> PlaylistControl {
+        let step = seek_step_secs as i64;
+        match self {
+            KeyAction::PlayPause => PlaylistControl::TogglePause,
+            KeyAction::SeekForward => PlaylistControl::SkipBy(step),
+            KeyAction::SeekBackward => PlaylistControl::SkipBy(-step),
+            KeyAction::Next => PlaylistControl::Skip,
+            KeyAction::Previous => PlaylistControl::Previous,
+        }
+    }
+    ///Recognizes line reedline returned for bound key
+    pub fn from_line(line: &str) -> Option<Self> {
+        let name = line.strip_prefix(KEY_COMMAND_PREFIX)?;
+        Self::ALL.into_iter().find(|action| action.name() == name)
+    }
+}
+
+pub fn default_keybindings() -> BTreeMap<String, KeyAction> {
+    BTreeMap::from([
+        ("ctrl-p".into(), KeyAction::PlayPause),
+        ("ctrl-right".into(), KeyAction::SeekForward),
+        ("ctrl-left".into(), KeyAction::SeekBackward),
+        ("alt-n".into(), KeyAction::Next),
+        ("alt-b".into(), KeyAction::Previous),
+    ])
+}
+
+///Parses keys like `ctrl-p`, `alt-shift-right` or `f5`
+fn parse_key(key: &str) -> Option<(KeyModifiers, KeyCode)> {
+    let key = key.to_lowercase();
+    let mut parts: Vec<&str> = key.split('-').collect();
+    let code = match parts.pop()? {
+        "left" => KeyCode::Left,
+        "right" => KeyCode::Right,
+        "up" => KeyCode::Up,
+        "down" => KeyCode::Down,
+        "home" => KeyCode::Home,
+        "end" => KeyCode::End,
+        "pageup" => KeyCode::PageUp,
+        "pagedown" => KeyCode::PageDown,
+        "space" => KeyCode::Char(' '),
+        name => match name.strip_prefix('f').and_then(|n| n.parse().ok()) {
+            Some(n) => KeyCode::F(n),
+            None => {
+                let mut chars = name.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => KeyCode::Char(c),
+                    _ => return None,
+                }
+            }
+        },
+    };
+    let mut modifiers = KeyModifiers::NONE;
+    for part in parts {
+        modifiers |= match part {
+            "ctrl" => KeyModifiers::CONTROL,
+            "alt" => KeyModifiers::ALT,
+            "shift" => KeyModifiers::SHIFT,
+            _ => return None,
+        };
+    }
+    Some((modifiers, code))
+}
+
+///Binds keys from config, returns those that couldn't be parsed
+pub fn add_key_actions(
+    keybindings: &mut Keybindings,
+    actions: &BTreeMap<String, KeyAction>,
+) -> Vec<String> {
+    let mut invalid = Vec::new();
+    for (key, action) in actions {
+        match parse_key(key) {
+            Some((modifiers, code)) => keybindings.add_binding(
+                modifiers,
+                code,
+                ReedlineEvent::ExecuteHostCommand(format!("{KEY_COMMAND_PREFIX}{}", action.name())),
+            ),
+            None => invalid.push(key.clone()),
+        }
+    }
+    invalid
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_keys_with_modifiers() {
+        assert_eq!(
+            parse_key("ctrl-p"),
+            Some((KeyModifiers::CONTROL, KeyCode::Char('p')))
+        );
+        assert_eq!(
+            parse_key("Alt-Shift-Right"),
+            Some((KeyModifiers::ALT | KeyModifiers::SHIFT, KeyCode::Right))
+        );
+        assert_eq!(parse_key("f5"), Some((KeyModifiers::NONE, KeyCode::F(5))));
+        assert_eq!(
+            parse_key("f"),
+            Some((KeyModifiers::NONE, KeyCode::Char('f')))
+        );
+        assert_eq!(
+            parse_key("alt-space"),
+            Some((KeyModifiers::ALT, KeyCode::Char(' ')))
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        assert_eq!(parse_key(""), None);
+        assert_eq!(parse_key("ctrl-"), None);
+        assert_eq!(parse_key("hyper-p"), None);
+        assert_eq!(parse_key("ctrl-pp"), None);
+    }
+
+    #[test]
+    fn default_keybindings_parse() {
+        for key in default_keybindings().keys() {
+            assert!(parse_key(key).is_some(), "{key}");
+        }
+    }
+
+    #[test]
+    fn recognizes_only_bound_key_lines() {
+        for action in KeyAction::ALL {
+            let line = format!("{KEY_COMMAND_PREFIX}{}", action.name());
+            assert_eq!(KeyAction::from_line(&line), Some(action));
+        }
+        assert_eq!(KeyAction::from_line("next"), None);
+        assert_eq!(
+            KeyAction::from_line(&format!("{KEY_COMMAND_PREFIX}stop")),
+            None
+        );
+    }
+}
@@ -0,0 +1,110 @@
+use crate::{error::Error, queue::Queue, utils::get_playlists};
+
+///Track found by `find`
+pub struct LibraryHit {
+    pub playlist: String,
+    ///Position in playlist, counted from 1
+    pub index: usize,
+    pub name: String,
+    pub uploader: Option<String>,
+    score: i64,
+}
+
+///Result of `find_tracks`
+pub struct FoundTracks {
+    pub hits: Vec<LibraryHit>,
+    ///Playlists whose queue couldn't be read, with the reason
+    pub skipped: Vec<(String, std::io::Error)>,
+}
+
+///Looks for tracks matching every word of query in name or uploader, best matches first.
+///Only reads queues, playlists with unreadable ones are skipped
+pub async fn find_tracks(query: &str) -> Result<FoundTracks, Error> {
+    let words: Vec<String> = query.split_whitespace().map(str::to_lowercase).collect();
+    if words.is_empty() {
+        return Err(Error::EmptyQuery);
+    }
+    let mut hits = Vec::new();
+    let mut skipped = Vec::new();
+    for playlist in get_playlists().await? {
+        let queue = match Queue::read_queue_json(&playlist).await {
+            Ok(Some(queue)) => queue,
+            Ok(None) => continue,
+            Err(e) => {
+                skipped.push((playlist, e));
+                continue;
+            }
+        };
+        for (i, item) in queue.items.into_iter().enumerate() {
+            let name = item.name.to_lowercase();
+            let uploader = item.uploader.as_deref().map(str::to_lowercase);
+            let score = words.iter().try_fold(0, |total, word| {
+                let best = fuzzy_score(word, &name)
+                    .max(uploader.as_deref().and_then(|u| fuzzy_score(word, u)))?;
+                Some(total + best)
+            });
+            if let Some(score) = score {
+                hits.push(LibraryHit {
+                    playlist: playlist.clone(),
+                    index: i + 1,
+                    name: item.name,
+                    uploader: item.uploader,
+                    score,
+                });
+            }
+        }
+    }
+    hits.sort_by_key(|hit| std::cmp::Reverse(hit.score));
+    Ok(FoundTracks { hits, skipped })
+}
+
+///Scores how well lowercase `word` matches lowercase `text`, None when its characters
+///don't all appear in order. Substrings beat scattered matches
+fn fuzzy_score(word: &str, text: &str) -> Option<i64> {
+    if let Some(pos) = text.find(word) {
+        let word_start = pos == 0 || !text[..pos].ends_with(char::is_alphanumeric);
+        return Some(100 + word.len() as i64 * 10 + if word_start { 50 } else { 0 });
+    }
+
+    let mut score = 0;
+    let mut last_match: Option<usize> = None;
+    let mut chars = text.chars().enumerate();
+    for wanted in word.chars() {
+        let (i, _) = chars.by_ref().find(|(_, c)| *c == wanted)?;
+        score += 10;
+        match last_match {
+            Some(last) if last + 1 == i => score += 15,
+            Some(last) => score -= (i - last) as i64,
+            None => {}
+        }
+        last_match = Some(i);
+    }
+    Some(score)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fuzzy_score_needs_characters_in_order() {
+        assert!(fuzzy_score("dpk", "daft punk").is_some());
+        assert_eq!(fuzzy_score("kpd", "daft punk"), None);
+        assert_eq!(fuzzy_score("x", "daft punk"), None);
+    }
+
+    #[test]
+    fn fuzzy_score_prefers_substrings_at_word_start() {
+        let word_start = fuzzy_score("punk", "daft punk").unwrap();
+        let inside = fuzzy_score("unk", "daft punk").unwrap();
+        let scattered = fuzzy_score("dpnk", "daft punk").unwrap();
+        assert!(word_start > inside);
+        assert!(inside > scattered);
+        assert!(fuzzy_score("daft", "daft punk") > fuzzy_score("aft", "daft punk"));
+    }
+
+    #[test]
+    fn fuzzy_score_prefers_adjacent_characters() {
+        assert!(fuzzy_score("dt", "dxt") > fuzzy_score("dt", "dxxxxt"));
+    }
+}
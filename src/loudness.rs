@@ -0,0 +1,189 @@
+use std::{f64::consts::PI, io::Cursor, path::Path};
+
+use rodio::{Decoder, Source};
+use serde::{Deserialize, Serialize};
+
+use crate::effects::Biquad;
+
+///Loudness ReplayGain 2.0 gain values bring audio to
+pub const REFERENCE_LUFS: f64 = -18.0;
+
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+const RELATIVE_GATE_LU: f64 = 10.0;
+
+///Gain values in dB relative to `REFERENCE_LUFS`, peaks as linear sample values
+#[derive(Deserialize, Serialize, Clone, Copy, Debug)]
+pub struct ReplayGain {
+    pub track_gain: f64,
+    pub track_peak: f32,
+    pub album_gain: f64,
+    pub album_peak: f32,
+}
+impl ReplayGain {
+    ///Linear factor bringing audio to `target_lufs` without clipping its peak
+    pub fn factor(&self, target_lufs: f64, album: bool) -> f32 {
+        let (gain, peak) = if album {
+            (self.album_gain, self.album_peak)
+        } else {
+            (self.track_gain, self.track_peak)
+        };
+        let factor = 10f64.powf((gain + target_lufs - REFERENCE_LUFS) / 20.0) as f32;
+        if peak > 0.0 {
+            factor.min(1.0 / peak)
+        } else {
+            factor
+        }
+    }
+}
+
+///Result of measuring one file as described in ITU-R BS.1770 / EBU R128
+pub struct TrackLoudness {
+    pub integrated: f64,
+    pub peak: f32,
+    ///Mean square of every 400ms gating block, kept to measure whole album
+    blocks: Vec<f64>,
+}
+
+pub fn analyze_file(
+    path: &Path,
+) -> Result<TrackLoudness, Box<dyn std::error::Error + Send + Sync>> {
+    let bytes = std::fs::read(path)?;
+    let decoder = Decoder::try_from(Cursor::new(bytes))?;
+    Ok(measure(decoder))
+}
+
+fn measure<S: Source>(source: S) -> TrackLoudness {
+    let channels = source.channels() as usize;
+    let rate = source.sample_rate() as f64;
+
+    let mut filters: Vec<[Biquad; 2]> = (0..channels).map(|_| k_weighting(rate)).collect();
+    let step = (rate * 0.1) as usize;
+    let mut steps: Vec<f64> = Vec::new();
+    let mut sum = 0.0;
+    let mut frames = 0;
+    let mut peak = 0f32;
+
+    for (i, sample) in source.enumerate() {
+        peak = peak.max(sample.abs());
+        let [pre, rlb] = &mut filters[i % channels];
+        let filtered = rlb.process(pre.process(sample as f64));
+        sum += filtered * filtered;
+        if i % channels == channels - 1 {
+            frames += 1;
+            if frames == step {
+                steps.push(sum / step as f64);
+                sum = 0.0;
+                frames = 0;
+            }
+        }
+    }
+
+    let blocks: Vec<f64> = steps
+        .windows(4)
+        .map(|w| w.iter().sum::<f64>() / 4.0)
+        .collect();
+
+    TrackLoudness {
+        integrated: gated_loudness(&blocks),
+        peak,
+        blocks,
+    }
+}
+
+///Loudness of all tracks as if they were played back to back
+pub fn album_loudness(tracks: &[&TrackLoudness]) -> f64 {
+    let blocks: Vec<f64> = tracks
+        .iter()
+        .flat_map(|t| t.blocks.iter().copied())
+        .collect();
+    gated_loudness(&blocks)
+}
+
+fn block_loudness(mean_square: f64) -> f64 {
+    -0.691 + 10.0 * mean_square.log10()
+}
+
+fn gated_loudness(blocks: &[f64]) -> f64 {
+    let mean = |blocks: &mut dyn Iterator<Item = &f64>| {
+        let (sum, count) = blocks.fold((0.0, 0usize), |(s, c), v| (s + v, c + 1));
+        (count > 0).then(|| sum / count as f64)
+    };
+
+    let absolute = |v: &&f64| block_loudness(**v) > ABSOLUTE_GATE_LUFS;
+    let Some(ungated) = mean(&mut blocks.iter().filter(absolute)) else {
+        return ABSOLUTE_GATE_LUFS;
+    };
+    let relative_gate = block_loudness(ungated) - RELATIVE_GATE_LU;
+    let gated = mean(
+        &mut blocks
+            .iter()
+            .filter(absolute)
+            .filter(|v| block_loudness(**v) > relative_gate),
+    );
+    gated.map_or(ABSOLUTE_GATE_LUFS, block_loudness)
+}
+
+///High shelf and high pass filters from BS.1770, computed for any sample rate
+fn k_weighting(rate: f64) -> [Biquad; 2] {
+    let f0 = 1681.974450955533;
+    let gain = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let pre = Biquad::new(
+        [
+            vh + vb * k / q + k * k,
+            2.0 * (k * k - vh),
+            vh - vb * k / q + k * k,
+        ],
+        [
+            1.0 + k / q + k * k,
+            2.0 * (k * k - 1.0),
+            1.0 - k / q + k * k,
+        ],
+    );
+
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let rlb = Biquad::new(
+        [a0, -2.0 * a0, a0],
+        [a0, 2.0 * (k * k - 1.0), 1.0 - k / q + k * k],
+    );
+    [pre, rlb]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rodio::source::SineWave;
+
+    use super::*;
+
+    #[test]
+    fn gating_drops_silent_and_quiet_blocks() {
+        assert_eq!(gated_loudness(&[]), ABSOLUTE_GATE_LUFS);
+        assert_eq!(gated_loudness(&[1e-9, 1e-9]), ABSOLUTE_GATE_LUFS);
+        let full = block_loudness(1.0);
+        assert!((gated_loudness(&[1.0, 1.0, 1e-9]) - full).abs() < 1e-9);
+        //-20 LUFS block is below relative gate of about -13.7 LUFS
+        assert!((gated_loudness(&[1.0, 0.01]) - full).abs() < 1e-9);
+        //-3.7 LUFS block stays above it
+        assert!((gated_loudness(&[1.0, 0.5]) - block_loudness(0.75)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn full_scale_sine_measures_minus_three_lufs() {
+        let sine = SineWave::new(997.0).take_duration(Duration::from_secs(3));
+        let loudness = measure(sine);
+        assert!(
+            (loudness.integrated + 3.01).abs() < 0.05,
+            "{}",
+            loudness.integrated
+        );
+        assert!((loudness.peak - 1.0).abs() < 1e-3);
+    }
+}
@@ -1,30 +1,106 @@
+use chrono::NaiveDate;
 use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
-use reedline::{DefaultPromptSegment, ExternalPrinter};
+use reedline::{
+    ColumnarMenu, DefaultHinter, Emacs, ExternalPrinter, KeyCode, KeyModifiers, MenuBuilder,
+    Reedline, ReedlineEvent, ReedlineMenu, default_emacs_keybindings,
+};
 
-use std::{path::PathBuf, sync::Arc};
-use tokio::sync::{
-    Mutex,
-    mpsc::{self, Sender},
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    sync::{
+        Mutex,
+        mpsc::{self, Sender},
+        oneshot, watch,
+    },
+    task::JoinHandle,
 };
 
 use crate::{
-    download::{download_youtube_playlist, download_youtube_video_audio},
-    playlist::{PlaylistControl, play_playlist},
-    queue::{Queue, handle_getting_queue, handle_removing_audio, handle_renaming_audio},
-    search::search_youtube,
+    completion::ReplCompleter,
+    config::{Config, valid_speed},
+    download::{
+        DownloadJobs, download_search_result, download_youtube_playlist,
+        download_youtube_video_audio,
+    },
+    equalizer::{EqSettings, PRESETS, parse_band},
+    history::SearchHistory,
+    keybindings::{KeyAction, add_key_actions},
+    library::{FoundTracks, LibraryHit, find_tracks},
+    loudness::{REFERENCE_LUFS, ReplayGain, album_loudness, analyze_file},
+    output::{OutputTarget, find_output_device, list_output_devices},
+    playlist::{
+        NowPlayingInfo, PlaybackStatus, PlaylistControl, SleepTimer, play_items, play_playlist,
+    },
+    prompt::ReplPrompt,
+    queue::{
+        Queue, QueueItem, handle_getting_queue, handle_removing_audio, handle_renaming_audio,
+        handle_trimming_audio,
+    },
+    repl_history::ReplHistory,
+    search::{SearchFilter, SearchSession, SearchSource, SearchType, Video, search_youtube},
     utils::{
-        Paths, create_playlist, get_default_path, get_playlists, get_programs_paths,
-        get_title_of_url,
+        Paths, create_playlist, format_duration, get_default_path, get_playlists,
+        get_programs_paths, get_title_of_url, parse_duration, parse_selection,
     },
 };
 
+mod completion;
+#[macro_use]
+mod console;
+mod config;
+mod daemon;
 mod download;
+mod effects;
+mod equalizer;
 mod error;
+mod history;
+mod http;
+mod keybindings;
+mod library;
+mod loudness;
+mod mpd;
+mod output;
 mod playlist;
+mod prompt;
 mod queue;
+mod repl_history;
 mod search;
+mod tui;
 mod utils;
 
+///How often one-shot and script runs check on background work
+const ONE_SHOT_POLL: Duration = Duration::from_millis(50);
+
+const COMPLETION_MENU: &str = "completion_menu";
+
+///Arguments program is started with, REPL starts when there are none
+#[derive(Parser, Debug)]
+#[command(
+    name = "yta-cli",
+    version = "1.0",
+    about = "CLI REPL tool for creating and playing playlists using yt-dlp",
+    args_conflicts_with_subcommands = true
+)]
+struct Args {
+    #[arg(long, help = "Run commands from file, one per line, instead of REPL")]
+    script: Option<PathBuf>,
+
+    #[arg(
+        long,
+        requires = "script",
+        help = "Don't stop script on failed command"
+    )]
+    keep_going: bool,
+
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+///One line of REPL or script input
 #[derive(Parser, Debug)]
 #[command(
     name = "yta-cli",
@@ -48,25 +124,84 @@ enum Commands {
     },
     ///Download YouTube playlist to local playlist
     DownloadPlaylist {
-        #[arg(help = "URL of the playlist")]
+        #[arg(help = "URL of the playlist or index of playlist from last search")]
         url: String,
 
         #[arg(short, long, help = "Name of playlist you want to add audio")]
         playlist_name: String,
     },
-    ///Search for youtube video
+    ///Search for videos, playlists or channels
     Search {
-        #[arg(help = "Query for youtube video")]
+        #[arg(help = "What to search for")]
         query: Vec<String>,
 
         #[arg(short, long, default_value_t = 1, help = "How many results you want")]
         number_of_results: u16,
+
+        #[arg(long, help = "Print results as JSON")]
+        json: bool,
+
+        #[arg(
+            long,
+            help = "Faster search which doesn't open every video, upload dates are missing"
+        )]
+        flat: bool,
+
+        #[arg(long, value_enum, default_value_t = SearchSource::Youtube, help = "Where to search")]
+        source: SearchSource,
+
+        #[arg(long, help = "Shortest result to keep, like `2m`")]
+        min_duration: Option<String>,
+
+        #[arg(long, help = "Longest result to keep, like `10m`")]
+        max_duration: Option<String>,
+
+        #[arg(
+            long,
+            help = "Keep results uploaded on this date or later, like `2024-01-31`"
+        )]
+        after: Option<String>,
+
+        #[arg(long, help = "Keep results from channels with this in their name")]
+        channel: Option<String>,
+
+        #[arg(long, help = "Drop results with this word in title, can be repeated")]
+        exclude: Vec<String>,
+
+        #[arg(long = "type", value_enum, default_value_t = SearchType::Video, help = "What kind of results to find")]
+        kind: SearchType,
     },
+    ///Shows next page of last search
+    Next,
+    ///Shows previous page of last search
+    Prev,
 
     ///Plays playlist
     Play {
-        #[arg(help = "Name of playlist you want to play")]
-        name: String,
+        #[arg(
+            required_unless_present = "hit",
+            help = "Name of playlist you want to play"
+        )]
+        name: Option<String>,
+
+        #[arg(short, long, help = "Number of track to start from")]
+        track: Option<usize>,
+
+        #[arg(long, conflicts_with_all = ["name", "track"], help = "Number of hit from last `find`")]
+        hit: Option<usize>,
+    },
+    ///Runs commands from file, one per line
+    Source {
+        #[arg(help = "Path of script, lines starting with `#` are skipped")]
+        file: PathBuf,
+
+        #[arg(long, help = "Don't stop on failed command")]
+        keep_going: bool,
+    },
+    ///Finds tracks in all playlists by name or uploader
+    Find {
+        #[arg(help = "Words to look for, letters may be skipped")]
+        query: Vec<String>,
     },
     ///Creates playlist
     Create {
@@ -82,13 +217,39 @@ enum Commands {
         #[arg(
             short,
             long,
-            default_value_t = 1,
-            help = "index of previous search result"
+            default_value = "1",
+            help = "Results of previous search like `2`, `1-3,5` or `all`"
         )]
-        result_index: u16,
+        result_index: String,
+
+        #[arg(long, help = "Take results from search number n of `history` instead")]
+        from_history: Option<usize>,
+    },
+    ///Lists previous searches
+    History {
+        #[command(subcommand)]
+        subcommand: Option<HistoryCommand>,
+    },
+    ///Searches and downloads top results in one step
+    Grab {
+        #[arg(help = "What to search for")]
+        query: Vec<String>,
+
+        #[arg(short, long, help = "Name of playlist you want to add audio")]
+        playlist_name: String,
+
+        #[arg(short, long, default_value_t = 1, help = "How many results you want")]
+        number_of_results: u16,
+
+        #[arg(long, value_enum, default_value_t = SearchSource::Youtube, help = "Where to search")]
+        source: SearchSource,
     },
     ///Displays all playlists
     GetPlaylists,
+    ///Opens full screen view of playlists, queue and playback
+    Tui,
+    ///Keeps running in background, other yta-cli processes send it commands
+    Daemon,
     ///Exit program
     Exit,
     ///Change current audio to previous
@@ -101,8 +262,11 @@ enum Commands {
     Skip,
     ///Skips current audio by some seconds
     SkipBy {
-        #[arg(help = "How many seconds you want to skip by")]
-        seconds: u64,
+        #[arg(
+            allow_negative_numbers = true,
+            help = "How many seconds you want to skip by, negative goes back"
+        )]
+        seconds: i64,
     },
     ///Renames audio in some playlist
     Rename {
@@ -121,28 +285,146 @@ enum Commands {
     },
     ///Get queue of playlist
     GetQueue { playlist_name: String },
+    ///Fades out and pauses playback after some time
+    Sleep {
+        #[arg(help = "Duration like `30m` or `1h30m`, `end-of-track` or `cancel`")]
+        timer: String,
+    },
+    ///Displays currently playing audio
+    Status,
+    ///Repeats section of current audio
+    Loop {
+        #[arg(help = "Start of section like `1:30`, or `off`")]
+        a: String,
+        #[arg(help = "End of section like `1:45`")]
+        b: Option<String>,
+    },
+    ///Changes playback speed
+    Speed {
+        #[arg(help = "Speed factor between 0.25 and 4, like 1.5")]
+        factor: f32,
+        #[arg(long, short, help = "Keep pitch unchanged by time stretching audio")]
+        keep_pitch: bool,
+    },
+    ///Measures loudness of every audio in playlist for normalization
+    Analyze {
+        #[arg(help = "Name of playlist you want to analyze")]
+        playlist_name: String,
+    },
+    ///Displays or changes equalizer settings
+    Eq {
+        #[command(subcommand)]
+        command: Option<EqCommand>,
+    },
+    ///Lists available audio output devices
+    Devices,
+    ///Displays or chooses where audio is played
+    Output {
+        #[arg(help = "Name of device from `devices`, or `default`")]
+        device: Option<String>,
+        #[arg(long, conflicts_with_all = ["device", "wav"], help = "Discard audio instead of playing it")]
+        null: bool,
+        #[arg(long, conflicts_with = "device", help = "Write audio to WAV file")]
+        wav: Option<PathBuf>,
+        #[arg(
+            long,
+            default_value_t = 1.0,
+            help = "How many times faster than real time null output plays"
+        )]
+        speed: f32,
+    },
+    ///Sets offsets skipped every time audio plays, omitted ones are cleared
+    Trim {
+        playlist_name: String,
+        #[arg(long, short, help = "Name of audio")]
+        name: String,
+        #[arg(long, help = "Where audio starts playing like `0:30`")]
+        start: Option<String>,
+        #[arg(long, help = "Where audio stops playing like `4:10`")]
+        end: Option<String>,
+    },
+}
+#[derive(Subcommand, Debug)]
+enum HistoryCommand {
+    ///Shows results of one previous search
+    Show {
+        #[arg(help = "Number of search in `history`, 1 is the most recent")]
+        number: usize,
+    },
+}
+#[derive(Subcommand, Debug)]
+enum EqCommand {
+    ///Sets gain of one band or preamp
+    Set {
+        #[arg(help = "Band number from 1 to 10, frequency like `1k` or `preamp`")]
+        band: String,
+        #[arg(allow_negative_numbers = true, help = "Gain in dB")]
+        gain: f32,
+    },
+    ///Applies named preset
+    Preset {
+        #[arg(value_parser = PRESETS)]
+        name: String,
+    },
+    ///Turns equalizer on
+    On,
+    ///Turns equalizer off
+    Off,
 }
 #[tokio::main]
 async fn main() {
-    println!("Welcome to yta-cli CLI REPL. Type `help` or `exit` to quit.");
-
-    let paths = get_programs_paths().await;
-    let mut control_playlist: Option<Sender<PlaylistControl>> = None;
-    let last_searched_ids: Arc<Mutex<Option<Vec<String>>>> = Arc::new(Mutex::new(None));
+    let args = Args::parse();
+    if args.command.is_none() && args.script.is_none() {
+        say!("Welcome to yta-cli CLI REPL. Type `help` or `exit` to quit.");
+    }
+    //Running daemon gets command given on command line, so it's not run here
+    if let Some(command) = &args.command
+        && daemon::forwards(command)
+        && let Some(ok) = daemon::send_command(std::env::args().skip(1).collect()).await
+    {
+        std::process::exit(if ok { 0 } else { 1 });
+    }
 
-    use reedline::{DefaultPrompt, Reedline, Signal};
     let printer = ExternalPrinter::default();
-    let mut line_editor = Reedline::create().with_external_printer(printer.clone());
-    let prompt = DefaultPrompt::new(
-        DefaultPromptSegment::Basic("yta-cli".into()),
-        DefaultPromptSegment::Empty,
-    );
+    let mut app = App {
+        paths: get_programs_paths().await,
+        printer: printer.clone(),
+        control_playlist: None,
+        last_found: Vec::new(),
+        last_search: Arc::new(Mutex::new(None)),
+        tasks: Vec::new(),
+        playback: None,
+        player: watch::channel(None).0,
+        downloads: DownloadJobs::default(),
+        daemon_client: args.command.is_none() && daemon::is_running().await,
+    };
+    //Command or script given on command line runs once without REPL
+    let outcome = if let Some(command) = args.command {
+        Some(run_command(command, &mut app).await)
+    } else if let Some(script) = &args.script {
+        Some(run_script(script, args.keep_going, &mut app).await)
+    } else {
+        None
+    };
+    if let Some(outcome) = outcome {
+        std::process::exit(finish(outcome, app).await);
+    }
+
+    use reedline::Signal;
+    let config = Config::load().await.unwrap_or_default();
+    let prompt = ReplPrompt::new(app.player.subscribe(), app.downloads.clone());
+    tokio::spawn(announce_playback(app.player.subscribe(), printer.clone()));
+    let mut line_editor = create_line_editor(printer, &config).await;
+    if app.daemon_client {
+        say!("Daemon is running, commands are sent to it");
+        tokio::spawn(daemon::follow_player(app.player.clone()));
+    }
 
     loop {
         let input = match line_editor.read_line(&prompt) {
             Ok(Signal::Success(buffer)) => buffer,
             Ok(Signal::CtrlD) | Ok(Signal::CtrlC) => {
-                println!("\nAborted!");
+                say!("\nAborted!");
                 break;
             }
             _ => {
@@ -150,185 +432,736 @@ async fn main() {
             }
         };
 
-        let trimmed = input.trim();
-        if trimmed.is_empty() {
+        if let Some(action) = KeyAction::from_line(&input) {
+            handle_key_action(action, &config, &mut app).await;
             continue;
         }
+        if run_line(&input, &mut app).await == Outcome::Exit {
+            break;
+        }
+    }
+}
 
-        let args = shell_words::split(trimmed);
-        if args.is_err() {
-            println!("Failed to parse input.");
-            continue;
+async fn create_line_editor(printer: ExternalPrinter<String>, config: &Config) -> Reedline {
+    let completion_menu = ColumnarMenu::default().with_name(COMPLETION_MENU);
+    let mut keybindings = default_emacs_keybindings();
+    keybindings.add_binding(
+        KeyModifiers::NONE,
+        KeyCode::Tab,
+        ReedlineEvent::UntilFound(vec![
+            ReedlineEvent::Menu(COMPLETION_MENU.into()),
+            ReedlineEvent::MenuNext,
+        ]),
+    );
+    for key in add_key_actions(&mut keybindings, &config.keybindings) {
+        say!("Unknown key in keybindings: {key}");
+    }
+
+    let line_editor = Reedline::create()
+        .with_external_printer(printer)
+        .with_completer(Box::new(ReplCompleter::new(Cli::command())))
+        .with_menu(ReedlineMenu::EngineCompleter(Box::new(completion_menu)))
+        .with_hinter(Box::new(DefaultHinter::default()))
+        .with_edit_mode(Box::new(Emacs::new(keybindings)));
+    match ReplHistory::open(config).await {
+        Ok(history) => line_editor.with_history(Box::new(history)),
+        Err(e) => {
+            say!("Failed to open command history: {}", e);
+            line_editor
         }
-        let default_path = get_default_path().await.unwrap();
-
-        let cli_args = args.unwrap();
-        match Cli::command()
-            .no_binary_name(true)
-            .try_get_matches_from(cli_args)
-        {
-            Ok(matches) => {
-                let cli = Cli::from_arg_matches(&matches).unwrap(); // safe unwrap
-                match cli.command {
-                    Commands::Previous => {
-                        if let Some(tx) = &control_playlist {
-                            handle_sending_playlist_control(tx, PlaylistControl::Previous).await;
-                        } else {
-                            println!("Currently no playlist is playing");
-                        }
-                    }
-                    Commands::Rename {
-                        current_name,
-                        target_name,
-                        playlist_name,
-                    } => {
-                        if let Err(e) =
-                            handle_renaming_audio(&playlist_name, target_name, &current_name).await
-                        {
-                            println!("Error while renaming audio: {e}");
-                        }
-                    }
-                    Commands::RemoveAudio {
-                        playlist_name,
-                        name,
-                    } => {
-                        if let Err(e) = handle_removing_audio(&name, &playlist_name).await {
-                            println!("Error while removing audio: {e}");
-                        }
-                    }
-                    Commands::GetQueue { playlist_name } => {
-                        match handle_getting_queue(&playlist_name).await {
-                            Ok(v) => println!("queue: {:?}", v),
-                            Err(e) => println!("Error while trying to get queue: {e}"),
-                        }
-                    }
-                    Commands::DownloadPlaylist { url, playlist_name } => {
-                        let paths = paths.clone();
-                        let printer = printer.clone();
-                        tokio::spawn(async move {
-                            handle_download_playlist(paths, url, playlist_name, printer).await;
-                        });
-                    }
+    }
+}
+
+///Applies playback key, in daemon when REPL is its client
+async fn handle_key_action(action: KeyAction, config: &Config, app: &mut App) {
+    if app.daemon_client {
+        if daemon::send_key(action).await.is_some() {
+            return;
+        }
+        say!("Daemon is not running anymore, commands run here from now on");
+        app.daemon_client = false;
+    }
+    if let Err(e) = apply_key_action(action, config.seek_step_secs, app) {
+        let _ = app.printer.sender().send(e);
+    }
+}
 
-                    Commands::DownloadResult { result_index, name } => {
-                        let arc_clone = Arc::clone(&last_searched_ids);
-                        let paths = paths.clone();
-                        let printer = printer.clone();
-                        tokio::spawn(async move {
-                            handle_download_last_search_result(
-                                result_index,
-                                name,
-                                paths,
-                                arc_clone,
-                                printer,
-                            )
-                            .await;
-                        });
+fn apply_key_action(action: KeyAction, seek_step_secs: u64, app: &App) -> Result<(), String> {
+    let Some(tx) = &app.control_playlist else {
+        return Err("Currently no playlist is playing".to_string());
+    };
+    tx.try_send(action.control(seek_step_secs))
+        .map_err(|e| format!("Error while trying to control playlist: {e}"))
+}
+
+///Parses one line of REPL or script input and runs it, `#` starts a comment line
+async fn run_line(input: &str, app: &mut App) -> Outcome {
+    let trimmed = input.trim();
+    if trimmed.is_empty() || trimmed.starts_with('#') {
+        return Outcome::Done;
+    }
+
+    let Ok(cli_args) = shell_words::split(trimmed) else {
+        say!("Failed to parse input.");
+        return Outcome::Failed;
+    };
+    run_args(cli_args, app).await
+}
+
+///Runs command given as words, in daemon when REPL is its client
+async fn run_args(args: Vec<String>, app: &mut App) -> Outcome {
+    let command = match parse_args(&args) {
+        Ok(v) => v,
+        Err(outcome) => return outcome,
+    };
+    if app.daemon_client && daemon::forwards(&command) && !matches!(command, Commands::Exit) {
+        match daemon::send_command(args).await {
+            Some(true) => return Outcome::Done,
+            Some(false) => return Outcome::Failed,
+            None => {
+                say!("Daemon is not running anymore, commands run here from now on");
+                app.daemon_client = false;
+            }
+        }
+    }
+    app.tasks.retain(|task| !task.is_finished());
+    run_command(command, app).await
+}
+
+///Parses words of one command, printing clap's message when they aren't one
+fn parse_args(args: &[String]) -> Result<Commands, Outcome> {
+    match Cli::command()
+        .no_binary_name(true)
+        .try_get_matches_from(args)
+    {
+        Ok(matches) => Ok(Cli::from_arg_matches(&matches).unwrap().command), // safe unwrap
+        Err(err) => {
+            use clap::error::ErrorKind;
+            match err.kind() {
+                ErrorKind::DisplayHelp | ErrorKind::DisplayVersion => {
+                    say!("{err}");
+                    Err(Outcome::Done)
+                }
+                _ => {
+                    say!("Error: {err}");
+                    Err(Outcome::Failed)
+                }
+            }
+        }
+    }
+}
+
+///Runs file line by line, each line waits for downloads it started before next one runs
+async fn run_script(path: &Path, keep_going: bool, app: &mut App) -> Outcome {
+    let script = match tokio::fs::read_to_string(path).await {
+        Ok(v) => v,
+        Err(e) => {
+            say!("Failed to read script {}: {e}", path.display());
+            return Outcome::Failed;
+        }
+    };
+
+    let earlier_tasks = std::mem::take(&mut app.tasks);
+    let mut outcome = Outcome::Done;
+    for (i, line) in script.lines().enumerate() {
+        let line_outcome = run_line(line, app).await;
+        let tasks_ok = wait_for_tasks(std::mem::take(&mut app.tasks), &app.printer).await;
+        if line_outcome == Outcome::Exit {
+            outcome = Outcome::Exit;
+            break;
+        }
+        if line_outcome == Outcome::Failed || !tasks_ok {
+            outcome = Outcome::Failed;
+            if !keep_going {
+                say!("Script stopped at {}:{}", path.display(), i + 1);
+                break;
+            }
+        }
+    }
+    app.tasks.extend(earlier_tasks);
+    outcome
+}
+
+///State shared by commands of one REPL session or one-shot run
+#[derive(Default)]
+struct App {
+    paths: Paths,
+    printer: ExternalPrinter<String>,
+    control_playlist: Option<Sender<PlaylistControl>>,
+    last_found: Vec<LibraryHit>,
+    last_search: Arc<Mutex<Option<SearchSession>>>,
+    ///Background work started by commands, resolving to whether it succeeded
+    tasks: Vec<JoinHandle<bool>>,
+    ///Playlist started last, kept apart so scripts don't wait for it after every line
+    playback: Option<JoinHandle<bool>>,
+    ///Track and pause state of playlist playing now
+    player: watch::Sender<Option<NowPlayingInfo>>,
+    downloads: DownloadJobs,
+    ///REPL sends commands to daemon found at start instead of running them
+    daemon_client: bool,
+}
+
+impl App {
+    ///Ends playback, dropping control channel also stops its output
+    fn stop_playback(&mut self) {
+        self.control_playlist = None;
+        if let Some(playback) = self.playback.take() {
+            playback.abort();
+        }
+        self.player.send_replace(None);
+    }
+    ///Plays `items` under playlist `name`, or that playlist's queue when they're `None`
+    fn start_playback(&mut self, name: String, start_index: usize, items: Option<Vec<QueueItem>>) {
+        let (tx, rx) = mpsc::channel(10);
+        self.control_playlist = Some(tx);
+        let player = self.player.clone();
+        let sender = self.printer.sender();
+        self.playback = Some(tokio::spawn(async move {
+            let result = match items {
+                Some(items) => play_items(&name, items, start_index, rx, player).await,
+                None => play_playlist(&name, start_index, rx, player).await,
+            };
+            match result {
+                Ok(_) => true,
+                Err(e) => {
+                    let _ = sender.send(format!("Error when trying to play playlist: {e}"));
+                    false
+                }
+            }
+        }));
+    }
+    ///Sends control to playback, returns whether anything is playing
+    fn control(&self, control: PlaylistControl) -> bool {
+        self.control_playlist
+            .as_ref()
+            .is_some_and(|tx| tx.try_send(control).is_ok())
+    }
+    async fn playback_status(&self) -> Option<PlaybackStatus> {
+        let (reply, rx) = oneshot::channel();
+        if !self.control(PlaylistControl::Status(reply)) {
+            return None;
+        }
+        rx.await.ok()
+    }
+}
+
+///Prints changes of playback, which also makes reedline redraw prompt showing them
+async fn announce_playback(
+    mut player: watch::Receiver<Option<NowPlayingInfo>>,
+    printer: ExternalPrinter<String>,
+) {
+    let sender = printer.sender();
+    let mut last = player.borrow_and_update().clone();
+    while player.changed().await.is_ok() {
+        let current = player.borrow_and_update().clone();
+        let message = match (&last, &current) {
+            (last, current) if last == current => continue,
+            (_, None) => "Playback finished".to_string(),
+            (Some(old), Some(new)) if old.playlist == new.playlist && old.index == new.index => {
+                if new.paused { "Paused" } else { "Resumed" }.to_string()
+            }
+            (_, Some(new)) => format!("Now playing: {}. {}", new.index + 1, new.track),
+        };
+        let _ = sender.send(message);
+        last = current;
+    }
+}
+
+///Waits for everything run from command line started, returns exit code
+async fn finish(outcome: Outcome, mut app: App) -> i32 {
+    let mut tasks = std::mem::take(&mut app.tasks);
+    tasks.extend(app.playback.take());
+    let success = wait_for_tasks(tasks, &app.printer).await;
+    if success && outcome != Outcome::Failed {
+        0
+    } else {
+        1
+    }
+}
+
+///Waits for background work and returns whether all of it succeeded. Prints its messages,
+///as nothing reads printer outside of REPL prompt
+async fn wait_for_tasks(tasks: Vec<JoinHandle<bool>>, printer: &ExternalPrinter<String>) -> bool {
+    while !tasks.iter().all(|task| task.is_finished()) {
+        print_pending(printer);
+        tokio::time::sleep(ONE_SHOT_POLL).await;
+    }
+    let mut success = true;
+    for task in tasks {
+        success &= task.await.unwrap_or(false);
+    }
+    print_pending(printer);
+    success
+}
+
+fn print_pending(printer: &ExternalPrinter<String>) {
+    while let Some(line) = printer.get_line() {
+        say!("{line}");
+    }
+}
+
+///What happened to command run by `run_command`
+#[derive(Clone, Copy, PartialEq)]
+enum Outcome {
+    Done,
+    Failed,
+    Exit,
+}
+
+async fn run_command(command: Commands, app: &mut App) -> Outcome {
+    match command {
+        Commands::Previous => {
+            if let Some(tx) = &app.control_playlist {
+                handle_sending_playlist_control(tx, PlaylistControl::Previous).await;
+            } else {
+                say!("Currently no playlist is playing");
+                return Outcome::Failed;
+            }
+        }
+        Commands::Rename {
+            current_name,
+            target_name,
+            playlist_name,
+        } => {
+            if let Err(e) = handle_renaming_audio(&playlist_name, target_name, &current_name).await
+            {
+                say!("Error while renaming audio: {e}");
+                return Outcome::Failed;
+            }
+        }
+        Commands::RemoveAudio {
+            playlist_name,
+            name,
+        } => {
+            if let Err(e) = handle_removing_audio(&name, &playlist_name).await {
+                say!("Error while removing audio: {e}");
+                return Outcome::Failed;
+            }
+        }
+        Commands::GetQueue { playlist_name } => match handle_getting_queue(&playlist_name).await {
+            Ok(v) => say!("queue: {:?}", v),
+            Err(e) => {
+                say!("Error while trying to get queue: {e}");
+                return Outcome::Failed;
+            }
+        },
+        Commands::DownloadPlaylist { url, playlist_name } => {
+            let url = match url.parse::<usize>() {
+                Ok(index) => match search_result_playlist(&app.last_search, index).await {
+                    Ok(url) => url,
+                    Err(e) => {
+                        say!("{e}");
+                        return Outcome::Failed;
                     }
+                },
+                Err(_) => url,
+            };
+            let paths = app.paths.clone();
+            let printer = app.printer.clone();
+            let job = app.downloads.start();
+            app.tasks.push(console::spawn(async move {
+                let _job = job;
+                handle_download_playlist(paths, url, playlist_name, printer).await
+            }));
+        }
 
-                    Commands::GetPlaylists => match get_playlists().await {
-                        Ok(playlists) => {
-                            for playlist in playlists {
-                                println!("{}", playlist);
-                            }
-                        }
-                        Err(e) => {
-                            println!("Error while trying to get playlists: {e}")
-                        }
-                    },
-                    Commands::Skip => {
-                        if let Some(tx) = &control_playlist {
-                            handle_sending_playlist_control(tx, PlaylistControl::Skip).await;
-                        } else {
-                            println!("Currently no playlist is skippable");
-                        }
+        Commands::DownloadResult {
+            result_index,
+            name,
+            from_history,
+        } => {
+            let arc_clone = Arc::clone(&app.last_search);
+            let paths = app.paths.clone();
+            let printer = app.printer.clone();
+            let job = app.downloads.start();
+            app.tasks.push(console::spawn(async move {
+                let _job = job;
+                match from_history {
+                    Some(number) => {
+                        handle_download_history_result(number, result_index, name, paths, printer)
+                            .await
                     }
-                    Commands::Pause => {
-                        if let Some(tx) = &control_playlist {
-                            handle_sending_playlist_control(tx, PlaylistControl::Pause).await;
-                        } else {
-                            println!("Currently no playlist is playing");
-                        }
+                    None => {
+                        handle_download_last_search_result(
+                            result_index,
+                            name,
+                            paths,
+                            arc_clone,
+                            printer,
+                        )
+                        .await
                     }
+                }
+            }));
+        }
+        Commands::History { subcommand } => return handle_history_command(subcommand).await,
 
-                    Commands::Resume => {
-                        if let Some(tx) = &control_playlist {
-                            handle_sending_playlist_control(tx, PlaylistControl::Play).await;
-                        } else {
-                            println!("Currently no playlist is paused");
-                        }
-                    }
-                    Commands::Download {
-                        urls,
-                        playlist_name,
-                    } => {
-                        let paths = paths.clone();
-                        let printer = printer.clone();
-                        tokio::spawn(async move {
-                            handle_download(urls, playlist_name, &paths, &default_path, printer)
-                                .await;
-                        });
+        Commands::Grab {
+            query,
+            playlist_name,
+            number_of_results,
+            source,
+        } => {
+            let session = SearchSession::new(
+                query.join(" "),
+                source,
+                SearchType::Video,
+                number_of_results.max(1) as usize,
+                false,
+                false,
+                SearchFilter::default(),
+            );
+            let arc_clone = Arc::clone(&app.last_search);
+            let paths = app.paths.clone();
+            let printer = app.printer.clone();
+            let job = app.downloads.start();
+            app.tasks.push(console::spawn(async move {
+                let _job = job;
+                handle_grab(session, playlist_name, paths, arc_clone, printer).await
+            }));
+        }
+
+        Commands::GetPlaylists => match get_playlists().await {
+            Ok(playlists) => {
+                for playlist in playlists {
+                    say!("{}", playlist);
+                }
+            }
+            Err(e) => {
+                say!("Error while trying to get playlists: {e}");
+                return Outcome::Failed;
+            }
+        },
+        Commands::Skip => {
+            if let Some(tx) = &app.control_playlist {
+                handle_sending_playlist_control(tx, PlaylistControl::Skip).await;
+            } else {
+                say!("Currently no playlist is skippable");
+                return Outcome::Failed;
+            }
+        }
+        Commands::Pause => {
+            if let Some(tx) = &app.control_playlist {
+                handle_sending_playlist_control(tx, PlaylistControl::Pause).await;
+            } else {
+                say!("Currently no playlist is playing");
+                return Outcome::Failed;
+            }
+        }
+
+        Commands::Resume => {
+            if let Some(tx) = &app.control_playlist {
+                handle_sending_playlist_control(tx, PlaylistControl::Play).await;
+            } else {
+                say!("Currently no playlist is paused");
+                return Outcome::Failed;
+            }
+        }
+        Commands::Download {
+            urls,
+            playlist_name,
+        } => {
+            let default_path = match get_default_path().await {
+                Ok(p) => p,
+                Err(e) => {
+                    say!("Failed to get default path: {e}");
+                    return Outcome::Failed;
+                }
+            };
+            let paths = app.paths.clone();
+            let printer = app.printer.clone();
+            let job = app.downloads.start();
+            app.tasks.push(console::spawn(async move {
+                let _job = job;
+                handle_download(urls, playlist_name, &paths, &default_path, printer).await
+            }));
+        }
+        Commands::Search {
+            query,
+            number_of_results,
+            json,
+            flat,
+            source,
+            kind,
+            min_duration,
+            max_duration,
+            after,
+            channel,
+            exclude,
+        } => {
+            let durations = (
+                min_duration.as_deref().map(parse_duration).transpose(),
+                max_duration.as_deref().map(parse_duration).transpose(),
+            );
+            let (min_duration, max_duration) = match durations {
+                (Ok(min), Ok(max)) => (min, max),
+                (Err(e), _) | (_, Err(e)) => {
+                    say!("Error: invalid duration filter: {e}");
+                    return Outcome::Failed;
+                }
+            };
+            let after = match after
+                .as_deref()
+                .map(|d| NaiveDate::parse_from_str(d, "%Y-%m-%d"))
+                .transpose()
+            {
+                Ok(v) => v,
+                Err(e) => {
+                    say!("Error: invalid date, expected YYYY-MM-DD: {e}");
+                    return Outcome::Failed;
+                }
+            };
+            if after.is_some() && (flat || kind != SearchType::Video) {
+                say!("Error: --after needs upload dates, which only non-flat video searches have");
+                return Outcome::Failed;
+            }
+            let filter = SearchFilter {
+                min_duration,
+                max_duration,
+                after,
+                channel: channel.map(|c| c.to_lowercase()),
+                exclude: exclude.iter().map(|w| w.to_lowercase()).collect(),
+            };
+            let arc_clone = Arc::clone(&app.last_search);
+            let paths = app.paths.clone();
+            let printer = app.printer.clone();
+            app.tasks.push(console::spawn(async move {
+                let session = SearchSession::new(
+                    query.join(" "),
+                    source,
+                    kind,
+                    number_of_results.max(1) as usize,
+                    flat,
+                    json,
+                    filter,
+                );
+                handle_search(session, paths, arc_clone, printer).await
+            }));
+        }
+        Commands::Next | Commands::Prev => {
+            let forward = matches!(command, Commands::Next);
+            let arc_clone = Arc::clone(&app.last_search);
+            let paths = app.paths.clone();
+            let printer = app.printer.clone();
+            app.tasks.push(console::spawn(async move {
+                handle_search_page(forward, paths, arc_clone, printer).await
+            }));
+        }
+        Commands::Create { name } => {
+            let sender = app.printer.sender();
+            app.tasks.push(console::spawn(async move {
+                match create_playlist(&name).await {
+                    Ok(_) => {
+                        let _ =
+                            sender.send(format!("Successfully created playlist of name {name}"));
+                        true
                     }
-                    Commands::Search {
-                        query,
-                        number_of_results,
-                    } => {
-                        let arc_clone = Arc::clone(&last_searched_ids);
-                        let printer = printer.clone();
-                        tokio::spawn(async move {
-                            if let Ok(results) =
-                                search_youtube(&query.join(" "), number_of_results as usize).await
-                            {
-                                *arc_clone.lock().await =
-                                    Some(results.iter().map(|x| x.id.clone()).collect());
-                                for result in results {
-                                    let _ =
-                                        printer.sender().send(format!("Video: {}", result.title));
-                                }
-                            }
-                        });
+                    Err(e) => {
+                        let _ = sender.send(format!("Error while creating playlist: {e}"));
+                        false
                     }
-                    Commands::Create { name } => {
-                        tokio::spawn(async move {
-                            if let Ok(_) = create_playlist(&name).await {
-                                println!("Successfully created playlist of name {}", name);
-                            }
-                        });
+                }
+            }));
+        }
+        Commands::Play { name, track, hit } => {
+            let (name, start_index) = match (name, hit) {
+                (Some(name), _) => (name, track.unwrap_or(1).saturating_sub(1)),
+                (None, Some(hit)) => match hit.checked_sub(1).and_then(|i| app.last_found.get(i)) {
+                    Some(found) => (found.playlist.clone(), found.index - 1),
+                    None => {
+                        say!("No hit number {hit} in last `find` results");
+                        return Outcome::Failed;
                     }
-                    Commands::Play { name } => {
-                        let (tx, rx) = mpsc::channel(10);
-                        control_playlist = Some(tx);
-                        tokio::spawn(async move {
-                            if let Err(e) = play_playlist(&name, rx).await {
-                                println!("Error when trying to play playlist: {e}")
-                            }
-                        });
+                },
+                (None, None) => return Outcome::Failed, // clap requires one of them
+            };
+            app.start_playback(name, start_index, None);
+        }
+        Commands::Find { query } => match find_tracks(&query.join(" ")).await {
+            Ok(FoundTracks { hits, skipped }) => {
+                for (playlist, e) in skipped {
+                    say!("Skipped playlist {playlist}: {e}");
+                }
+                if hits.is_empty() {
+                    say!("No tracks found");
+                }
+                for (i, hit) in hits.iter().enumerate() {
+                    let uploader = hit
+                        .uploader
+                        .as_deref()
+                        .map(|u| format!(" ({u})"))
+                        .unwrap_or_default();
+                    say!(
+                        "{:>3}. {} #{}  {}{uploader}",
+                        i + 1,
+                        hit.playlist,
+                        hit.index,
+                        hit.name
+                    );
+                }
+                app.last_found = hits;
+            }
+            Err(e) => {
+                say!("Error while searching playlists: {e}");
+                return Outcome::Failed;
+            }
+        },
+        Commands::SkipBy { seconds } => {
+            if let Some(tx) = &app.control_playlist {
+                handle_sending_playlist_control(tx, PlaylistControl::SkipBy(seconds)).await;
+            } else {
+                say!("Currently no playlist is skippable");
+                return Outcome::Failed;
+            }
+        }
+        Commands::Sleep { timer } => {
+            let timer = if timer == "cancel" {
+                None
+            } else {
+                match timer.parse::<SleepTimer>() {
+                    Ok(v) => Some(v),
+                    Err(e) => {
+                        say!("Error: {e}");
+                        return Outcome::Failed;
                     }
-                    Commands::SkipBy { seconds } => {
-                        if let Some(tx) = &control_playlist {
-                            handle_sending_playlist_control(tx, PlaylistControl::SkipBy(seconds))
-                                .await;
-                        } else {
-                            println!("Currently no playlist is skippable");
-                        }
+                }
+            };
+            if let Some(tx) = &app.control_playlist {
+                handle_sending_playlist_control(tx, PlaylistControl::Sleep(timer)).await;
+            } else {
+                say!("Currently no playlist is playing");
+                return Outcome::Failed;
+            }
+        }
+        Commands::Status => {
+            if let Some(tx) = &app.control_playlist {
+                let (reply_tx, reply_rx) = oneshot::channel();
+                handle_sending_playlist_control(tx, PlaylistControl::Status(reply_tx)).await;
+                match reply_rx.await {
+                    Ok(status) => say!("{status}"),
+                    Err(_) => {
+                        say!("Currently no playlist is playing");
+                        return Outcome::Failed;
                     }
-                    Commands::Exit => {
-                        println!("Goodbye!");
-                        break;
+                }
+            } else {
+                say!("Currently no playlist is playing");
+                return Outcome::Failed;
+            }
+        }
+        Commands::Loop { a, b } => {
+            let section = if a == "off" {
+                None
+            } else {
+                match (parse_duration(&a), b.as_deref().map(parse_duration)) {
+                    (Ok(a), Some(Ok(b))) if a < b => Some((a, b)),
+                    _ => {
+                        say!("Error: expected `loop <a> <b>` with a before b");
+                        return Outcome::Failed;
                     }
                 }
+            };
+            if let Some(tx) = &app.control_playlist {
+                handle_sending_playlist_control(tx, PlaylistControl::Loop(section)).await;
+            } else {
+                say!("Currently no playlist is playing");
+                return Outcome::Failed;
             }
-            Err(err) => {
-                use clap::error::ErrorKind;
-                let msg = match err.kind() {
-                    ErrorKind::DisplayHelp | ErrorKind::DisplayVersion => err.to_string(),
-                    _ => format!("Error: {err}"),
-                };
-
-                println!("{}", msg);
+        }
+        Commands::Speed { factor, keep_pitch } => {
+            if !(0.25..=4.0).contains(&factor) {
+                say!("Error: speed has to be between 0.25 and 4");
+                return Outcome::Failed;
+            }
+            if let Some(tx) = &app.control_playlist {
+                handle_sending_playlist_control(tx, PlaylistControl::Speed { factor, keep_pitch })
+                    .await;
+            } else {
+                say!("Currently no playlist is playing");
+                return Outcome::Failed;
             }
         }
+        Commands::Analyze { playlist_name } => {
+            let printer = app.printer.clone();
+            app.tasks.push(console::spawn(async move {
+                handle_analyze_playlist(playlist_name, printer).await
+            }));
+        }
+        Commands::Eq { command } => {
+            return handle_eq_command(command, &app.control_playlist).await;
+        }
+        Commands::Devices => match list_output_devices() {
+            Ok(devices) => {
+                for device in devices {
+                    let marker = if device.is_default { " (default)" } else { "" };
+                    say!("{}{marker}", device.name);
+                }
+            }
+            Err(e) => {
+                say!("Error while listing devices: {e}");
+                return Outcome::Failed;
+            }
+        },
+        Commands::Output {
+            device,
+            null,
+            wav,
+            speed,
+        } => {
+            let target = match (device, null, wav) {
+                (_, true, _) => OutputTarget::Null { speed },
+                (_, _, Some(path)) => OutputTarget::Wav(path),
+                (Some(device), _, _) => {
+                    OutputTarget::Device((device != "default").then_some(device))
+                }
+                (None, false, None) => {
+                    return match Config::load().await {
+                        Ok(config) => {
+                            say!("{}", config.output_target());
+                            Outcome::Done
+                        }
+                        Err(e) => {
+                            say!("Failed to load config: {e}");
+                            Outcome::Failed
+                        }
+                    };
+                }
+            };
+            return handle_output_command(target, &app.control_playlist).await;
+        }
+        Commands::Trim {
+            playlist_name,
+            name,
+            start,
+            end,
+        } => {
+            let offsets = (
+                start.as_deref().map(parse_duration).transpose(),
+                end.as_deref().map(parse_duration).transpose(),
+            );
+            let (start, end) = match offsets {
+                (Ok(start), Ok(end)) => (start, end),
+                (Err(e), _) | (_, Err(e)) => {
+                    say!("Error: invalid trim offset: {e}");
+                    return Outcome::Failed;
+                }
+            };
+            if let Err(e) = handle_trimming_audio(&playlist_name, &name, start, end).await {
+                say!("Error while trimming audio: {e}");
+                return Outcome::Failed;
+            }
+        }
+        Commands::Source { file, keep_going } => {
+            return Box::pin(run_script(&file, keep_going, app)).await;
+        }
+        Commands::Tui => return tui::run(app).await,
+        Commands::Daemon => return daemon::serve(app).await,
+        Commands::Exit => {
+            say!("Goodbye!");
+            return Outcome::Exit;
+        }
     }
+    Outcome::Done
 }
 async fn handle_sending_playlist_control(
     tx: &Sender<PlaylistControl>,
@@ -336,7 +1169,7 @@ async fn handle_sending_playlist_control(
 ) {
     let control_str = display_playlist_control(&playlist_control);
     if let Err(e) = tx.send(playlist_control).await {
-        println!("Error while trying to: {control_str},error: {e}")
+        say!("Error while trying to: {control_str},error: {e}")
     }
 }
 fn display_playlist_control(playlist_control: &PlaylistControl) -> String {
@@ -345,33 +1178,147 @@ fn display_playlist_control(playlist_control: &PlaylistControl) -> String {
         PlaylistControl::Skip => "Skip".into(),
         PlaylistControl::Pause => "Pause".into(),
         PlaylistControl::Play => "Play".into(),
+        PlaylistControl::TogglePause => "Toggle Pause".into(),
         PlaylistControl::SkipBy(v) => format!("Skip By {}", v),
+        PlaylistControl::Seek(position) => format!("Seek {}", format_duration(*position)),
+        PlaylistControl::Sleep(Some(timer)) => format!("Sleep {}", timer),
+        PlaylistControl::Sleep(None) => "Cancel Sleep".into(),
+        PlaylistControl::Loop(Some((a, b))) => {
+            format!("Loop {}-{}", format_duration(*a), format_duration(*b))
+        }
+        PlaylistControl::Loop(None) => "Loop Off".into(),
+        PlaylistControl::Speed { factor, .. } => format!("Speed {}", factor),
+        PlaylistControl::Eq(_) => "Change Equalizer".into(),
+        PlaylistControl::Output { .. } => "Change Output".into(),
+        PlaylistControl::Status(_) => "Status".into(),
+        PlaylistControl::Queue(_) => "Queue".into(),
+        PlaylistControl::Enqueue(item) => format!("Enqueue {}", item.name),
+        PlaylistControl::Volume(v) => format!("Volume {:.0}%", v * 100.0),
     }
 }
 
+async fn handle_eq_command(
+    command: Option<EqCommand>,
+    control_playlist: &Option<Sender<PlaylistControl>>,
+) -> Outcome {
+    let mut config = match Config::load().await {
+        Ok(c) => c,
+        Err(e) => {
+            say!("Failed to load config: {e}");
+            return Outcome::Failed;
+        }
+    };
+    let Some(command) = command else {
+        say!("{}", config.eq);
+        return Outcome::Done;
+    };
+
+    match command {
+        EqCommand::Set { band, gain } => {
+            if !gain.is_finite() {
+                say!("Error: gain has to be a finite number of dB");
+                return Outcome::Failed;
+            }
+            let gain = gain.clamp(-24.0, 24.0);
+            if band == "preamp" {
+                config.eq.preamp = gain;
+            } else if let Some(i) = parse_band(&band) {
+                config.eq.bands[i] = gain;
+            } else {
+                say!("Error: unknown band {band}");
+                return Outcome::Failed;
+            }
+            config.eq.enabled = true;
+        }
+        EqCommand::Preset { name } => {
+            if let Some(preset) = EqSettings::preset(&name) {
+                config.eq = preset;
+            }
+        }
+        EqCommand::On => config.eq.enabled = true,
+        EqCommand::Off => config.eq.enabled = false,
+    }
+
+    let saved = match config.save().await {
+        Ok(_) => Outcome::Done,
+        Err(e) => {
+            say!("Failed to save config: {e}");
+            Outcome::Failed
+        }
+    };
+    if let Some(tx) = control_playlist {
+        handle_sending_playlist_control(tx, PlaylistControl::Eq(config.eq)).await;
+    }
+    say!("{}", config.eq);
+    saved
+}
+
+async fn handle_output_command(
+    target: OutputTarget,
+    control_playlist: &Option<Sender<PlaylistControl>>,
+) -> Outcome {
+    match &target {
+        OutputTarget::Device(Some(name)) => {
+            if let Err(e) = find_output_device(name) {
+                say!("Error: {e}");
+                return Outcome::Failed;
+            }
+        }
+        OutputTarget::Null { speed } if !valid_speed(*speed) => {
+            say!("Error: speed has to be a finite number greater than 0");
+            return Outcome::Failed;
+        }
+        _ => {}
+    }
+
+    if let Some(tx) = control_playlist {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        handle_sending_playlist_control(
+            tx,
+            PlaylistControl::Output {
+                target: target.clone(),
+                reply: reply_tx,
+            },
+        )
+        .await;
+        if let Ok(Err(e)) = reply_rx.await {
+            say!("Failed to switch output: {e}");
+            return Outcome::Failed;
+        }
+    }
+
+    let mut config = match Config::load().await {
+        Ok(c) => c,
+        Err(e) => {
+            say!("Failed to load config: {e}");
+            return Outcome::Failed;
+        }
+    };
+    say!("Output: {target}");
+    config.set_output_target(target);
+    if let Err(e) = config.save().await {
+        say!("Failed to save config: {e}");
+        return Outcome::Failed;
+    }
+    Outcome::Done
+}
+
 async fn handle_download(
     urls: Vec<String>,
     playlist_name: String,
     paths: &Paths,
-    default_path: &PathBuf,
+    default_path: &Path,
     printer: ExternalPrinter<String>,
-) {
+) -> bool {
     let sender = printer.sender();
-    let mut queue = match Queue::from_queue_json(&playlist_name).await {
-        Ok(q) => q,
-        Err(e) => {
-            println!("Failed to load queue: {e}");
-            return;
-        }
-    };
-
+    let mut success = true;
     for url in urls {
         let paths = paths.clone();
-        let title = match get_title_of_url(paths.yt_dlp_path.clone(), &url).await {
+        let title = match get_title_of_url(&paths, &url).await {
             Ok(t) => t,
             Err(e) => {
                 let _ = sender.send(format!("Failed to get title for URL: {e}"));
-                let _ = sender.send(format!("Failed to get title for URL: {e}"));
+                success = false;
                 continue;
             }
         };
@@ -384,120 +1331,468 @@ async fn handle_download(
 
         match download_youtube_video_audio(paths.clone(), &url, output_path.clone()).await {
             Ok(_) => {
-                queue.items.push(queue::QueueItem {
-                    file_path: output_path.to_string_lossy().to_string(),
-                    name: title,
-                });
-                if let Err(e) = queue.to_json(&playlist_name).await {
+                let item = queue::QueueItem::new(output_path.to_string_lossy().to_string(), title);
+                if let Err(e) = Queue::append_to_json(&playlist_name, item).await {
                     let _ = sender.send(format!(
                         "Failed to save json of playlist: {playlist_name},error: {e}"
                     ));
-                    return;
+                    return false;
                 }
                 let _ = sender.send(format!("Downloaded: {filename}"));
             }
             Err(e) => {
                 let _ = sender.send(format!("Download failed: {e}"));
+                success = false;
             }
         }
     }
+    success
 }
 
-pub async fn handle_download_last_search_result(
-    result_index: u16,
-    name: String,
+pub async fn handle_search(
+    mut session: SearchSession,
     paths: Paths,
-    last_searched_ids: Arc<Mutex<Option<Vec<String>>>>,
+    last_search: Arc<Mutex<Option<SearchSession>>>,
     printer: ExternalPrinter<String>,
-) {
+) -> bool {
     let sender = printer.sender();
+    //Flat results miss upload dates `--after` filters by
+    if session.filter.after.is_none() && Config::load().await.is_ok_and(|c| c.flat_search) {
+        session.flat = true;
+    }
+    let Some(videos) = fetch_search_page(&session, 0, &paths, &printer).await else {
+        return false;
+    };
+    if videos.is_empty() {
+        let _ = sender.send(format!("No results found for: {}", session.query));
+        return false;
+    }
 
-    let ids_lock = last_searched_ids.lock().await;
-    let Some(ids) = &*ids_lock else {
+    session.push_page(videos);
+    print_search_page(&session, 0, &printer);
+    record_search(&session, &printer).await;
+    *last_search.lock().await = Some(session);
+    true
+}
+
+pub async fn handle_search_page(
+    forward: bool,
+    paths: Paths,
+    last_search: Arc<Mutex<Option<SearchSession>>>,
+    printer: ExternalPrinter<String>,
+) -> bool {
+    let sender = printer.sender();
+    let mut lock = last_search.lock().await;
+    let Some(session) = &mut *lock else {
         let _ = sender.send("No previous search results found.".into());
-        return;
+        return false;
+    };
+    let page = if forward {
+        session.page + 1
+    } else if session.page == 0 {
+        let _ = sender.send("Already on first page.".into());
+        return false;
+    } else {
+        session.page - 1
+    };
+
+    if session.cached_page(page).is_some() {
+        print_search_page(session, page, &printer);
+        session.page = page;
+        return true;
+    }
+    let Some(videos) = fetch_search_page(session, page, &paths, &printer).await else {
+        return false;
+    };
+    if videos.is_empty() {
+        let _ = sender.send(format!("No more results for: {}", session.query));
+        return false;
+    }
+    session.push_page(videos);
+    print_search_page(session, page, &printer);
+    session.page = page;
+    record_search(session, &printer).await;
+    true
+}
+
+async fn fetch_search_page(
+    session: &SearchSession,
+    page: usize,
+    paths: &Paths,
+    printer: &ExternalPrinter<String>,
+) -> Option<Vec<Video>> {
+    let sender = printer.sender();
+    let results = match search_youtube(paths, session, page).await {
+        Ok(r) => r,
+        Err(e) => {
+            let _ = sender.send(format!("Search failed: {e}"));
+            return None;
+        }
     };
+    for e in &results.errors {
+        let _ = sender.send(format!("Failed to parse search result: {e}"));
+    }
+    Some(results.videos)
+}
 
-    let selected_idx = (result_index as usize).saturating_sub(1);
-    if selected_idx >= ids.len() {
+fn print_search_page(session: &SearchSession, page: usize, printer: &ExternalPrinter<String>) {
+    let sender = printer.sender();
+    let videos = session.cached_page(page).map_or(&[][..], Vec::as_slice);
+    if videos.is_empty() {
         let _ = sender.send(format!(
-            "Invalid index. Please choose between 1 and {}.",
-            ids.len()
+            "All results on page {} were filtered out, `next` to browse",
+            page + 1
         ));
         return;
     }
+    if session.json {
+        match serde_json::to_string_pretty(videos) {
+            Ok(v) => {
+                let _ = sender.send(v);
+            }
+            Err(e) => {
+                let _ = sender.send(format!("Failed to serialize results: {e}"));
+            }
+        }
+    } else {
+        let first = session.first_index(page);
+        for (i, result) in videos.iter().enumerate() {
+            let _ = sender.send(result.row(first + i));
+        }
+        let _ = sender.send(format!("Page {}, `next`/`prev` to browse", page + 1));
+    }
+}
 
-    let selected_id = ids[selected_idx].clone();
-    let title = match get_title_of_url(paths.yt_dlp_path.clone(), &selected_id).await {
-        Ok(t) => t,
-        Err(e) => {
-            let _ = sender.send(format!("Failed to fetch video title: {e}"));
-            return;
+///URL of playlist or channel found by last search
+async fn search_result_playlist(
+    last_search: &Mutex<Option<SearchSession>>,
+    index: usize,
+) -> Result<String, String> {
+    let lock = last_search.lock().await;
+    let Some(session) = &*lock else {
+        return Err("No previous search results found.".into());
+    };
+    if session.kind == SearchType::Video {
+        return Err(format!(
+            "Last search found videos, use `download-result <playlist> -r {index}`"
+        ));
+    }
+    match session.get(index) {
+        Some(result) => Ok(result.link()),
+        None => Err(format!(
+            "Invalid index. Result {index} isn't on any fetched page."
+        )),
+    }
+}
+
+pub async fn handle_download_last_search_result(
+    selection: String,
+    name: String,
+    paths: Paths,
+    last_search: Arc<Mutex<Option<SearchSession>>>,
+    printer: ExternalPrinter<String>,
+) -> bool {
+    let sender = printer.sender();
+
+    let videos: Vec<Video> = {
+        let lock = last_search.lock().await;
+        let Some(session) = &*lock else {
+            let _ = sender.send("No previous search results found.".into());
+            return false;
+        };
+        if session.kind != SearchType::Video {
+            let _ = sender.send(format!(
+                "Last search found {}s, use `download-playlist <index> -p {name}`",
+                session.kind
+            ));
+            return false;
+        }
+        let total = session.result_count();
+        match parse_selection(&selection, total) {
+            Some(indexes) => indexes
+                .into_iter()
+                .filter_map(|i| session.get(i).cloned())
+                .collect(),
+            None => {
+                let _ = sender.send(format!(
+                    "Invalid selection `{selection}`. Please choose results between 1 and {total}."
+                ));
+                return false;
+            }
         }
     };
+    download_search_results(&videos, &name, &paths, &printer).await
+}
 
-    let default_path = match get_default_path().await {
-        Ok(p) => p,
+pub async fn handle_download_history_result(
+    number: usize,
+    selection: String,
+    name: String,
+    paths: Paths,
+    printer: ExternalPrinter<String>,
+) -> bool {
+    let sender = printer.sender();
+    let history = match SearchHistory::load().await {
+        Ok(h) => h,
         Err(e) => {
-            let _ = sender.send(format!("Failed to get default path: {e}"));
-            return;
+            let _ = sender.send(format!("Failed to load search history: {e}"));
+            return false;
         }
     };
+    let Some(entry) = history.get(number) else {
+        let _ = sender.send(format!("No search number {number} in history."));
+        return false;
+    };
+    if entry.kind != SearchType::Video {
+        let _ = sender.send(format!(
+            "Search {number} found {}s, they can't be downloaded as audio",
+            entry.kind
+        ));
+        return false;
+    }
+    let total = entry.results.len();
+    let Some(indexes) = parse_selection(&selection, total) else {
+        let _ = sender.send(format!(
+            "Invalid selection `{selection}`. Please choose results between 1 and {total}."
+        ));
+        return false;
+    };
+    let videos: Vec<Video> = indexes
+        .into_iter()
+        .map(|i| entry.results[i - 1].to_video())
+        .collect();
+    download_search_results(&videos, &name, &paths, &printer).await
+}
 
-    let sanitezed = sanitize_filename::sanitize(title.trim());
-    let filename = format!("{}.mp3", sanitezed);
-    let output_path = default_path.join("playlists").join(&name).join(&filename);
-
-    let mut queue = match Queue::from_queue_json(&name).await {
-        Ok(q) => q,
+async fn handle_history_command(subcommand: Option<HistoryCommand>) -> Outcome {
+    let history = match SearchHistory::load().await {
+        Ok(h) => h,
         Err(e) => {
-            let _ = sender.send(format!("Failed to load playlist queue: {e}"));
-            return;
+            say!("Failed to load search history: {e}");
+            return Outcome::Failed;
         }
     };
+    match subcommand {
+        None => {
+            if history.entries.is_empty() {
+                say!("Search history is empty");
+            }
+            for (i, entry) in history.entries.iter().rev().enumerate() {
+                say!("{:>3}. {entry}", i + 1);
+            }
+        }
+        Some(HistoryCommand::Show { number }) => {
+            let Some(entry) = history.get(number) else {
+                say!("No search number {number} in history.");
+                return Outcome::Failed;
+            };
+            say!("{entry}");
+            for (i, result) in entry.results.iter().enumerate() {
+                say!(
+                    "{:>3}. {}  {}",
+                    i + 1,
+                    result.title,
+                    result.url.as_deref().unwrap_or(&result.id)
+                );
+            }
+        }
+    }
+    Outcome::Done
+}
 
-    if let Err(e) =
-        download_youtube_video_audio(paths.clone(), &selected_id, output_path.clone()).await
-    {
-        let _ = sender.send(format!("Download failed: {e}"));
-    } else {
-        queue.items.push(queue::QueueItem {
-            file_path: output_path.to_string_lossy().to_string(),
-            name: sanitezed,
-        });
+///Saves session into search history, reporting failure
+async fn record_search(session: &SearchSession, printer: &ExternalPrinter<String>) {
+    if let Err(e) = SearchHistory::record(session).await {
+        let _ = printer
+            .sender()
+            .send(format!("Failed to save search history: {e}"));
+    }
+}
 
-        if let Err(e) = queue.to_json(&name).await {
-            let _ = sender.send(format!("Failed to save queue: {e}"));
-        } else {
-            let _ = sender.send(format!("Download complete: {filename}"));
+///Downloads results one after another, reporting each of them
+async fn download_search_results(
+    videos: &[Video],
+    playlist_name: &str,
+    paths: &Paths,
+    printer: &ExternalPrinter<String>,
+) -> bool {
+    let sender = printer.sender();
+    let mut downloaded = 0;
+    for video in videos {
+        match download_search_result(paths.clone(), video, playlist_name).await {
+            Ok(filename) => {
+                downloaded += 1;
+                let _ = sender.send(format!("Download complete: {filename}"));
+            }
+            Err(e) => {
+                let _ = sender.send(format!("Download of {} failed: {e}", video.title));
+            }
         }
     }
+    if videos.len() > 1 {
+        let _ = sender.send(format!(
+            "Downloaded {downloaded}/{} results into {playlist_name}",
+            videos.len()
+        ));
+    }
+    downloaded == videos.len()
+}
+
+pub async fn handle_grab(
+    mut session: SearchSession,
+    playlist_name: String,
+    paths: Paths,
+    last_search: Arc<Mutex<Option<SearchSession>>>,
+    printer: ExternalPrinter<String>,
+) -> bool {
+    let sender = printer.sender();
+    if Config::load().await.is_ok_and(|c| c.flat_search) {
+        session.flat = true;
+    }
+    let Some(videos) = fetch_search_page(&session, 0, &paths, &printer).await else {
+        return false;
+    };
+    session.push_page(videos);
+    let videos = session.cached_page(0).cloned().unwrap_or_default();
+    if videos.is_empty() {
+        let _ = sender.send(format!("No results found for: {}", session.query));
+        return false;
+    }
+    record_search(&session, &printer).await;
+    *last_search.lock().await = Some(session);
+
+    download_search_results(&videos, &playlist_name, &paths, &printer).await
 }
 pub async fn handle_download_playlist(
     paths: Paths,
     url: String,
     playlist_name: String,
     printer: ExternalPrinter<String>,
-) {
+) -> bool {
+    let sender = printer.sender();
+    match download_youtube_playlist(paths, &url, &playlist_name).await {
+        Ok(_) => {
+            let _ = sender.send(format!("Successfully downloaded playlist: {playlist_name}"));
+            true
+        }
+        Err(e) => {
+            let _ = sender.send(format!("Failed to download YouTube playlist: {e}"));
+            false
+        }
+    }
+}
+pub async fn handle_analyze_playlist(
+    playlist_name: String,
+    printer: ExternalPrinter<String>,
+) -> bool {
     let sender = printer.sender();
-    let mut queue = match Queue::from_queue_json(&playlist_name).await {
+    let queue = match Queue::from_queue_json(&playlist_name).await {
         Ok(q) => q,
         Err(e) => {
             let _ = sender.send(format!("Failed to load playlist queue: {e}"));
-            return;
+            return false;
         }
     };
 
-    match download_youtube_playlist(paths, &url, &playlist_name, &mut queue).await {
-        Ok(_) => {
-            if let Err(e) = queue.to_json(&playlist_name).await {
-                let _ = sender.send(format!("Failed to save updated queue: {e}"));
-            } else {
-                let _ = sender.send(format!("Successfully downloaded playlist: {playlist_name}"));
+    let total = queue.items.len();
+    let mut measured = Vec::new();
+    for (i, item) in queue.items.iter().enumerate() {
+        let path = PathBuf::from(&item.file_path);
+        match tokio::task::spawn_blocking(move || analyze_file(&path)).await {
+            Ok(Ok(loudness)) => {
+                let _ = sender.send(format!(
+                    "Analyzed {}/{total}: {} ({:.1} LUFS)",
+                    i + 1,
+                    item.name,
+                    loudness.integrated
+                ));
+                measured.push((item.file_path.clone(), loudness));
+            }
+            Ok(Err(e)) => {
+                let _ = sender.send(format!("Failed to analyze {}: {e}", item.name));
+            }
+            Err(e) => {
+                let _ = sender.send(format!("Failed to analyze {}: {e}", item.name));
             }
         }
-        Err(e) => {
-            let _ = sender.send(format!("Failed to download YouTube playlist: {e}"));
+    }
+
+    if measured.is_empty() {
+        let _ = sender.send(format!(
+            "No track of playlist {playlist_name} could be analyzed"
+        ));
+        return false;
+    }
+    let tracks: Vec<_> = measured.iter().map(|(_, v)| v).collect();
+    let album_gain = REFERENCE_LUFS - album_loudness(&tracks);
+    let album_peak = tracks.iter().map(|v| v.peak).fold(0.0, f32::max);
+
+    // queue could have changed while analyzing, so only measured items get updated
+    let saved = Queue::update(&playlist_name, |queue| {
+        for item in queue.items.iter_mut() {
+            if let Some((_, loudness)) = measured.iter().find(|(path, _)| *path == item.file_path) {
+                item.replay_gain = Some(ReplayGain {
+                    track_gain: REFERENCE_LUFS - loudness.integrated,
+                    track_peak: loudness.peak,
+                    album_gain,
+                    album_peak,
+                });
+            }
         }
+        Ok(())
+    })
+    .await;
+    if let Err(e) = saved {
+        let _ = sender.send(format!("Failed to save queue: {e}"));
+        return false;
+    }
+    let _ = sender.send(format!(
+        "Analyzed playlist {playlist_name}, album gain: {album_gain:+.1} dB"
+    ));
+    measured.len() == total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn run(script: &str, keep_going: bool) -> Outcome {
+        let path = std::env::temp_dir().join(format!("yta-cli-script-{}", std::process::id()));
+        tokio::fs::write(&path, script).await.unwrap();
+        let outcome = run_script(&path, keep_going, &mut App::default()).await;
+        let _ = tokio::fs::remove_file(&path).await;
+        outcome
+    }
+
+    fn exists(playlist: &str) -> bool {
+        utils::playlist_dir(playlist).unwrap().exists()
+    }
+
+    #[tokio::test]
+    async fn script_skips_comments_and_stops_at_failure() {
+        let script =
+            "# create script-commented\n\n  create script-first\npause\ncreate script-after\n";
+        assert!(run(script, false).await == Outcome::Failed);
+        assert!(exists("script-first"));
+        assert!(!exists("script-commented") && !exists("script-after"));
+
+        let script = "pause\ncreate script-kept-going\n";
+        assert!(run(script, true).await == Outcome::Failed);
+        assert!(exists("script-kept-going"));
+
+        assert!(run("create script-ok\n# exit\n", false).await == Outcome::Done);
+        assert!(run("exit\ncreate script-exited\n", false).await == Outcome::Exit);
+        assert!(!exists("script-exited"));
+    }
+
+    #[tokio::test]
+    async fn background_work_fails_when_any_task_does() {
+        let printer = ExternalPrinter::default();
+        let tasks = vec![tokio::spawn(async { true }), tokio::spawn(async { true })];
+        assert!(wait_for_tasks(tasks, &printer).await);
+        let tasks = vec![tokio::spawn(async { true }), tokio::spawn(async { false })];
+        assert!(!wait_for_tasks(tasks, &printer).await);
+        let tasks = vec![tokio::spawn(async { panic!("task failed") })];
+        assert!(!wait_for_tasks(tasks, &printer).await);
     }
 }
@@ -0,0 +1,825 @@
+use std::{
+    path::Path,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    net::{TcpListener, TcpStream, tcp::OwnedReadHalf},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        oneshot,
+    },
+};
+
+use crate::{
+    App,
+    config::Config,
+    playlist::{PlaybackStatus, PlaylistControl},
+    queue::{Queue, QueueItem},
+    utils::{get_playlists, get_playlists_dir},
+};
+
+const GREETING: &str = "OK MPD 0.23.5\n";
+///Playlist shown for tracks added by clients while nothing played
+const QUEUE_NAME: &str = "mpd";
+const COMMANDS: [&str; 30] = [
+    "add",
+    "clear",
+    "close",
+    "command_list_begin",
+    "command_list_end",
+    "command_list_ok_begin",
+    "commands",
+    "currentsong",
+    "idle",
+    "listplaylist",
+    "listplaylistinfo",
+    "listplaylists",
+    "lsinfo",
+    "next",
+    "noidle",
+    "notcommands",
+    "outputs",
+    "pause",
+    "ping",
+    "play",
+    "playid",
+    "playlistinfo",
+    "previous",
+    "seek",
+    "seekcur",
+    "seekid",
+    "setvol",
+    "stats",
+    "status",
+    "stop",
+];
+
+///Subsystems `idle` reports, each with fields of `status` that change together with it
+const IDLE_SUBSYSTEMS: [(&str, &[&str]); 3] = [
+    ("player", &["state", "songid"]),
+    ("playlist", &["playlist"]),
+    ("mixer", &["volume"]),
+];
+///How often `idle` checks status for changes
+const IDLE_POLL: Duration = Duration::from_millis(500);
+
+const ACK_ARG: u8 = 2;
+const ACK_UNKNOWN: u8 = 5;
+const ACK_NO_EXIST: u8 = 50;
+const ACK_SYSTEM: u8 = 52;
+
+///Error sent to client as `ACK [code@index] {command} message`
+pub struct Ack {
+    code: u8,
+    message: String,
+}
+impl Ack {
+    fn new(code: u8, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+///Command of MPD client, run by daemon as it owns playback
+pub struct MpdRequest {
+    words: Vec<String>,
+    reply: oneshot::Sender<Result<String, Ack>>,
+}
+
+///Accepts MPD clients on localhost until returned receiver is dropped
+pub async fn listen(port: u16) -> Result<Receiver<MpdRequest>, std::io::Error> {
+    Ok(accept_clients(
+        TcpListener::bind(("127.0.0.1", port)).await?,
+    ))
+}
+
+fn accept_clients(listener: TcpListener) -> Receiver<MpdRequest> {
+    let (tx, rx) = mpsc::channel(16);
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    if let Ok((stream, _)) = accepted {
+                        tokio::spawn(serve_client(stream, tx.clone()));
+                    }
+                }
+                _ = tx.closed() => break,
+            }
+        }
+    });
+    rx
+}
+
+async fn serve_client(
+    stream: TcpStream,
+    requests: Sender<MpdRequest>,
+) -> Result<(), std::io::Error> {
+    let (read, mut write) = stream.into_split();
+    write.write_all(GREETING.as_bytes()).await?;
+    let mut lines = BufReader::new(read).lines();
+    //Commands of list being received, and whether each answers with `list_OK`
+    let mut list: Option<(Vec<String>, bool)> = None;
+
+    while let Some(line) = lines.next_line().await? {
+        let response = match (list.as_mut(), line.trim()) {
+            (None, "command_list_begin") => {
+                list = Some((Vec::new(), false));
+                continue;
+            }
+            (None, "command_list_ok_begin") => {
+                list = Some((Vec::new(), true));
+                continue;
+            }
+            (None, "close") => break,
+            //Only ends `idle`, which reads it itself
+            (None, "noidle") => continue,
+            (None, command) if command.split_whitespace().next() == Some("idle") => {
+                let subsystems: Vec<&str> = command.split_whitespace().skip(1).collect();
+                match idle(&subsystems, &mut lines, &requests).await? {
+                    Some(response) => response,
+                    None => break,
+                }
+            }
+            (None, _) => run_commands(&[line], false, &requests).await,
+            (Some((commands, list_ok)), "command_list_end") => {
+                let response = run_commands(commands, *list_ok, &requests).await;
+                list = None;
+                response
+            }
+            (Some((commands, _)), _) => {
+                commands.push(line);
+                continue;
+            }
+        };
+        write.write_all(response.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+///Runs commands until one fails, answer ends with `OK` or with `ACK` of failed one
+async fn run_commands(commands: &[String], list_ok: bool, requests: &Sender<MpdRequest>) -> String {
+    let mut response = String::new();
+    for (i, command) in commands.iter().enumerate() {
+        match request(command, requests).await {
+            Ok(output) => {
+                response.push_str(&output);
+                if list_ok {
+                    response.push_str("list_OK\n");
+                }
+            }
+            Err(Ack { code, message }) => {
+                let name = command.split_whitespace().next().unwrap_or_default();
+                response.push_str(&format!("ACK [{code}@{i}] {{{name}}} {message}\n"));
+                return response;
+            }
+        }
+    }
+    response.push_str("OK\n");
+    response
+}
+
+///Waits for change of one of `subsystems`, all when empty, or for `noidle`. `None` when
+///client disconnected meanwhile
+async fn idle(
+    subsystems: &[&str],
+    lines: &mut Lines<BufReader<OwnedReadHalf>>,
+    requests: &Sender<MpdRequest>,
+) -> Result<Option<String>, std::io::Error> {
+    let watched: Vec<_> = IDLE_SUBSYSTEMS
+        .iter()
+        .filter(|(name, _)| subsystems.is_empty() || subsystems.contains(name))
+        .collect();
+    let before = request("status", requests).await.unwrap_or_default();
+    let mut ticker = tokio::time::interval(IDLE_POLL);
+    loop {
+        tokio::select! {
+            //Clients may send only `noidle` while idle, it's answered with nothing changed
+            line = lines.next_line() => return Ok(line?.map(|_| "OK\n".to_string())),
+            _ = ticker.tick() => {
+                let now = match request("status", requests).await {
+                    Ok(v) => v,
+                    Err(Ack { code, message }) => {
+                        return Ok(Some(format!("ACK [{code}@0] {{idle}} {message}\n")));
+                    }
+                };
+                let changed: String = watched
+                    .iter()
+                    .filter(|(_, fields)| {
+                        fields
+                            .iter()
+                            .any(|field| status_field(&before, field) != status_field(&now, field))
+                    })
+                    .map(|(name, _)| format!("changed: {name}\n"))
+                    .collect();
+                if !changed.is_empty() {
+                    return Ok(Some(changed + "OK\n"));
+                }
+            }
+        }
+    }
+}
+
+fn status_field<'a>(status: &'a str, name: &str) -> Option<&'a str> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(": "))
+}
+
+async fn request(command: &str, requests: &Sender<MpdRequest>) -> Result<String, Ack> {
+    let words = shell_words::split(command)
+        .map_err(|_| Ack::new(ACK_ARG, "Invalid quoting of arguments"))?;
+    if words.is_empty() {
+        return Err(Ack::new(ACK_UNKNOWN, "No command given"));
+    }
+    let (reply, rx) = oneshot::channel();
+    let stopped = || Ack::new(ACK_SYSTEM, "Daemon is exiting");
+    requests
+        .send(MpdRequest { words, reply })
+        .await
+        .map_err(|_| stopped())?;
+    rx.await.map_err(|_| stopped())?
+}
+
+fn arg<T: FromStr>(args: &[String], i: usize) -> Result<T, Ack> {
+    let value = args
+        .get(i)
+        .ok_or_else(|| Ack::new(ACK_ARG, "Too few arguments"))?;
+    value
+        .parse()
+        .map_err(|_| Ack::new(ACK_ARG, format!("Invalid argument \"{value}\"")))
+}
+
+fn optional_arg<T: FromStr>(args: &[String], i: usize) -> Result<Option<T>, Ack> {
+    match args.get(i) {
+        Some(_) => arg(args, i).map(Some),
+        None => Ok(None),
+    }
+}
+
+fn seconds(value: f64) -> Result<Duration, Ack> {
+    Duration::try_from_secs_f64(value)
+        .map_err(|_| Ack::new(ACK_ARG, format!("Invalid time \"{value}\"")))
+}
+
+///Parses song position `N` or range `START:END` of playlist with `len` songs
+fn parse_range(value: &str, len: usize) -> Result<std::ops::Range<usize>, Ack> {
+    let invalid = || Ack::new(ACK_ARG, format!("Invalid song range \"{value}\""));
+    let range = match value.split_once(':') {
+        Some((start, "")) => start.parse().map_err(|_| invalid())?..len,
+        Some((start, end)) => {
+            start.parse().map_err(|_| invalid())?..end.parse().map_err(|_| invalid())?
+        }
+        None => {
+            let pos: usize = value.parse().map_err(|_| invalid())?;
+            pos..pos + 1
+        }
+    };
+    if range.start > range.end || range.end > len {
+        return Err(Ack::new(ACK_ARG, "Bad song index"));
+    }
+    Ok(range)
+}
+
+///Playlists directory serves as music directory, so track downloaded to playlist
+///`mix` gets `mix/<file>`, others keep their full path
+fn song_uri(playlists_dir: &Path, item: &QueueItem) -> String {
+    match Path::new(&item.file_path).strip_prefix(playlists_dir) {
+        Ok(relative) => relative.to_string_lossy().into_owned(),
+        Err(_) => item.file_path.clone(),
+    }
+}
+
+fn format_song(uri: String, item: &QueueItem, duration: Option<Duration>) -> String {
+    let mut song = format!("file: {uri}\nTitle: {}\n", item.name);
+    if let Some(uploader) = &item.uploader {
+        song.push_str(&format!("Artist: {uploader}\n"));
+    }
+    if let Some(duration) = duration {
+        song.push_str(&format!(
+            "Time: {}\nduration: {:.3}\n",
+            duration.as_secs(),
+            duration.as_secs_f64()
+        ));
+    }
+    song
+}
+
+async fn playlists_dir() -> Result<std::path::PathBuf, Ack> {
+    get_playlists_dir()
+        .await
+        .map_err(|e| Ack::new(ACK_SYSTEM, e.to_string()))
+}
+
+///Loads playlist without creating it, as loading missing one would
+async fn load_playlist(name: &str) -> Result<Vec<QueueItem>, Ack> {
+    let playlists = get_playlists()
+        .await
+        .map_err(|e| Ack::new(ACK_SYSTEM, e.to_string()))?;
+    if !playlists.iter().any(|playlist| playlist == name) {
+        return Err(Ack::new(ACK_NO_EXIST, "No such playlist"));
+    }
+    Queue::from_queue_json(name)
+        .await
+        .map(|queue| queue.items)
+        .map_err(|e| Ack::new(ACK_SYSTEM, e.to_string()))
+}
+
+///Finds track by uri, `mix` being whole playlist and `mix/<file>` one of its tracks
+async fn find_songs(uri: &str) -> Result<Vec<QueueItem>, Ack> {
+    let uri = uri.trim_end_matches('/');
+    if let Ok(items) = load_playlist(uri.trim_start_matches('/')).await {
+        return Ok(items);
+    }
+    let dir = playlists_dir().await?;
+    let playlists = get_playlists()
+        .await
+        .map_err(|e| Ack::new(ACK_SYSTEM, e.to_string()))?;
+    for playlist in playlists {
+        let items = load_playlist(&playlist).await?;
+        if let Some(item) = items.into_iter().find(|item| song_uri(&dir, item) == uri) {
+            return Ok(vec![item]);
+        }
+    }
+    Err(Ack::new(ACK_NO_EXIST, "No such song"))
+}
+
+///Queue clients see and edit, follows playback and stays after it stops
+pub struct Mpd {
+    queue: Vec<QueueItem>,
+    ///Playlist queue came from, playing it again shows the same one
+    name: String,
+    ///Changes with queue, so clients know to reload it
+    version: u32,
+    ///Percent, applied again to each playback started by clients
+    volume: u8,
+    ///Start of daemon, for `uptime` of `stats`
+    started: Instant,
+}
+impl Default for Mpd {
+    fn default() -> Self {
+        Self {
+            queue: Vec::new(),
+            name: QUEUE_NAME.to_string(),
+            version: 1,
+            volume: 100,
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Mpd {
+    pub async fn answer(&mut self, request: MpdRequest, app: &mut App) {
+        let result = self.handle(&request.words, app).await;
+        let _ = request.reply.send(result);
+    }
+
+    async fn handle(&mut self, words: &[String], app: &mut App) -> Result<String, Ack> {
+        let Some((command, args)) = words.split_first() else {
+            return Err(Ack::new(ACK_UNKNOWN, "No command given"));
+        };
+        let status = app.playback_status().await;
+        self.sync_queue(app, status.as_ref()).await;
+
+        match command.as_str() {
+            "ping" => Ok(String::new()),
+            "commands" => Ok(COMMANDS
+                .iter()
+                .map(|name| format!("command: {name}\n"))
+                .collect()),
+            "notcommands" => Ok(String::new()),
+            "outputs" => {
+                let target = Config::load().await.unwrap_or_default().output_target();
+                Ok(format!(
+                    "outputid: 0\noutputname: {target}\nplugin: yta-cli\noutputenabled: 1\n"
+                ))
+            }
+            "stats" => self.stats().await,
+            "status" => Ok(self.status(status.as_ref())),
+            "currentsong" => {
+                let Some(status) = status else {
+                    return Ok(String::new());
+                };
+                self.queue_songs(status.index..status.index + 1, Some(&status))
+                    .await
+            }
+            "playlistinfo" => {
+                let range = match args.first() {
+                    Some(value) => parse_range(value, self.queue.len())?,
+                    None => 0..self.queue.len(),
+                };
+                self.queue_songs(range, status.as_ref()).await
+            }
+            "play" => self.play(app, status, optional_arg(args, 0)?),
+            "playid" => {
+                let id: Option<usize> = optional_arg(args, 0)?;
+                self.play(app, status, id.map(|id| id.saturating_sub(1)))
+            }
+            "pause" => {
+                let control = match optional_arg::<u8>(args, 0)? {
+                    Some(0) => PlaylistControl::Play,
+                    Some(_) => PlaylistControl::Pause,
+                    None => PlaylistControl::TogglePause,
+                };
+                app.control(control);
+                Ok(String::new())
+            }
+            "stop" => {
+                app.stop_playback();
+                Ok(String::new())
+            }
+            "next" => {
+                app.control(PlaylistControl::Skip);
+                Ok(String::new())
+            }
+            "previous" => {
+                app.control(PlaylistControl::Previous);
+                Ok(String::new())
+            }
+            "seek" => self.seek(app, status, arg(args, 0)?, seconds(arg(args, 1)?)?),
+            "seekid" => {
+                let id: usize = arg(args, 0)?;
+                self.seek(app, status, id.saturating_sub(1), seconds(arg(args, 1)?)?)
+            }
+            "seekcur" => {
+                let value: String = arg(args, 0)?;
+                let time: f64 = arg(args, 0)?;
+                let control = if value.starts_with(['+', '-']) {
+                    PlaylistControl::SkipBy(time.round() as i64)
+                } else {
+                    PlaylistControl::Seek(seconds(time)?)
+                };
+                app.control(control);
+                Ok(String::new())
+            }
+            "setvol" => {
+                let volume: u8 = arg(args, 0)?;
+                if volume > 100 {
+                    return Err(Ack::new(ACK_ARG, "Invalid volume value"));
+                }
+                self.volume = volume;
+                app.control(PlaylistControl::Volume(f32::from(volume) / 100.0));
+                Ok(String::new())
+            }
+            "add" => {
+                let items = find_songs(&arg::<String>(args, 0)?).await?;
+                if status.is_some() {
+                    for item in &items {
+                        app.control(PlaylistControl::Enqueue(item.clone()));
+                    }
+                }
+                self.queue.extend(items);
+                self.version += 1;
+                Ok(String::new())
+            }
+            "clear" => {
+                app.stop_playback();
+                self.queue.clear();
+                self.name = QUEUE_NAME.to_string();
+                self.version += 1;
+                Ok(String::new())
+            }
+            "listplaylists" => {
+                let playlists = get_playlists()
+                    .await
+                    .map_err(|e| Ack::new(ACK_SYSTEM, e.to_string()))?;
+                Ok(playlists
+                    .iter()
+                    .map(|name| format!("playlist: {name}\n"))
+                    .collect())
+            }
+            "listplaylist" => {
+                let dir = playlists_dir().await?;
+                let items = load_playlist(&arg::<String>(args, 0)?).await?;
+                Ok(items
+                    .iter()
+                    .map(|item| format!("file: {}\n", song_uri(&dir, item)))
+                    .collect())
+            }
+            "listplaylistinfo" => {
+                let dir = playlists_dir().await?;
+                let items = load_playlist(&arg::<String>(args, 0)?).await?;
+                Ok(items
+                    .iter()
+                    .map(|item| format_song(song_uri(&dir, item), item, None))
+                    .collect())
+            }
+            "lsinfo" => {
+                let uri: Option<String> = optional_arg(args, 0)?;
+                match uri.as_deref().map(|uri| uri.trim_matches('/')) {
+                    None | Some("") => {
+                        let playlists = get_playlists()
+                            .await
+                            .map_err(|e| Ack::new(ACK_SYSTEM, e.to_string()))?;
+                        Ok(playlists
+                            .iter()
+                            .map(|name| format!("directory: {name}\nplaylist: {name}\n"))
+                            .collect())
+                    }
+                    Some(uri) => {
+                        let dir = playlists_dir().await?;
+                        let items = find_songs(uri).await?;
+                        Ok(items
+                            .iter()
+                            .map(|item| format_song(song_uri(&dir, item), item, None))
+                            .collect())
+                    }
+                }
+            }
+            _ => Err(Ack::new(
+                ACK_UNKNOWN,
+                format!("unknown command \"{command}\""),
+            )),
+        }
+    }
+
+    ///Takes over queue of playback, which may have been started or extended outside of MPD
+    async fn sync_queue(&mut self, app: &App, status: Option<&PlaybackStatus>) {
+        let Some(status) = status else {
+            return;
+        };
+        let (reply, rx) = oneshot::channel();
+        if !app.control(PlaylistControl::Queue(reply)) {
+            return;
+        }
+        let Ok(queue) = rx.await else {
+            return;
+        };
+        let same = queue.len() == self.queue.len()
+            && queue
+                .iter()
+                .zip(&self.queue)
+                .all(|(a, b)| a.file_path == b.file_path);
+        if !same {
+            self.queue = queue;
+            self.version += 1;
+        }
+        self.name = status.playlist.clone();
+    }
+
+    ///Every track of every playlist counts as song, tags like artists aren't known
+    async fn stats(&self) -> Result<String, Ack> {
+        let playlists = get_playlists()
+            .await
+            .map_err(|e| Ack::new(ACK_SYSTEM, e.to_string()))?;
+        let mut songs = 0;
+        for playlist in playlists {
+            songs += load_playlist(&playlist).await?.len();
+        }
+        Ok(format!(
+            "artists: 0\nalbums: 0\nsongs: {songs}\nuptime: {}\nplaytime: 0\ndb_playtime: 0\ndb_update: 0\n",
+            self.started.elapsed().as_secs()
+        ))
+    }
+
+    fn status(&self, status: Option<&PlaybackStatus>) -> String {
+        let volume = match status {
+            Some(status) => (status.volume * 100.0).round() as u32,
+            None => u32::from(self.volume),
+        };
+        let mut response = format!(
+            "volume: {volume}\nrepeat: 0\nrandom: 0\nsingle: 0\nconsume: 0\nplaylist: {}\nplaylistlength: {}\n",
+            self.version,
+            self.queue.len()
+        );
+        let Some(status) = status else {
+            response.push_str("state: stop\n");
+            return response;
+        };
+        let state = if status.paused { "pause" } else { "play" };
+        let elapsed = status.position.as_secs_f64();
+        response.push_str(&format!(
+            "state: {state}\nsong: {}\nsongid: {}\nelapsed: {elapsed:.3}\n",
+            status.index,
+            status.index + 1
+        ));
+        if let Some(duration) = status.duration {
+            response.push_str(&format!(
+                "time: {}:{}\nduration: {:.3}\n",
+                status.position.as_secs(),
+                duration.as_secs(),
+                duration.as_secs_f64()
+            ));
+        }
+        response
+    }
+
+    ///Songs of queue with their positions, ids are positions counted from 1
+    async fn queue_songs(
+        &self,
+        range: std::ops::Range<usize>,
+        status: Option<&PlaybackStatus>,
+    ) -> Result<String, Ack> {
+        let dir = playlists_dir().await?;
+        let mut response = String::new();
+        for pos in range {
+            let Some(item) = self.queue.get(pos) else {
+                break;
+            };
+            let duration = status
+                .filter(|status| status.index == pos)
+                .and_then(|status| status.duration);
+            response.push_str(&format_song(song_uri(&dir, item), item, duration));
+            response.push_str(&format!("Pos: {pos}\nId: {}\n", pos + 1));
+        }
+        Ok(response)
+    }
+
+    ///Resumes without position, otherwise starts queue again from song at it
+    fn play(
+        &mut self,
+        app: &mut App,
+        status: Option<PlaybackStatus>,
+        pos: Option<usize>,
+    ) -> Result<String, Ack> {
+        if pos.is_none() && status.is_some() {
+            app.control(PlaylistControl::Play);
+            return Ok(String::new());
+        }
+        let pos = pos.unwrap_or_default();
+        if pos >= self.queue.len() {
+            return Err(Ack::new(ACK_ARG, "Bad song index"));
+        }
+        app.start_playback(self.name.clone(), pos, Some(self.queue.clone()));
+        if self.volume != 100 {
+            app.control(PlaylistControl::Volume(f32::from(self.volume) / 100.0));
+        }
+        Ok(String::new())
+    }
+
+    ///Seeks within current song, other one just starts playing from its beginning
+    fn seek(
+        &mut self,
+        app: &mut App,
+        status: Option<PlaybackStatus>,
+        pos: usize,
+        time: Duration,
+    ) -> Result<String, Ack> {
+        if status.as_ref().is_some_and(|status| status.index == pos) {
+            app.control(PlaylistControl::Seek(time));
+            return Ok(String::new());
+        }
+        self.play(app, status, Some(pos))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    };
+
+    use tokio::{io::AsyncReadExt, net::tcp::OwnedWriteHalf};
+
+    use super::*;
+
+    ///Server on free port whose daemon knows `status`, `play` and `ping`
+    async fn stub_server() -> std::net::SocketAddr {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut requests = accept_clients(listener);
+        let playing = Arc::new(AtomicBool::new(false));
+        tokio::spawn(async move {
+            while let Some(MpdRequest { words, reply }) = requests.recv().await {
+                let response = match words[0].as_str() {
+                    "status" => {
+                        let state = if playing.load(Ordering::SeqCst) {
+                            "play"
+                        } else {
+                            "stop"
+                        };
+                        Ok(format!("volume: 100\nstate: {state}\n"))
+                    }
+                    "play" => {
+                        playing.store(true, Ordering::SeqCst);
+                        Ok(String::new())
+                    }
+                    "ping" => Ok(String::new()),
+                    name => Err(Ack::new(ACK_UNKNOWN, format!("unknown command \"{name}\""))),
+                };
+                let _ = reply.send(response);
+            }
+        });
+        address
+    }
+
+    struct Client {
+        lines: Lines<BufReader<OwnedReadHalf>>,
+        write: OwnedWriteHalf,
+    }
+    impl Client {
+        async fn connect(address: std::net::SocketAddr) -> (Self, String) {
+            let (read, write) = TcpStream::connect(address).await.unwrap().into_split();
+            let mut lines = BufReader::new(read).lines();
+            let greeting = lines.next_line().await.unwrap().unwrap();
+            (Self { lines, write }, greeting)
+        }
+        async fn send(&mut self, text: &str) {
+            self.write.write_all(text.as_bytes()).await.unwrap();
+        }
+        ///Lines of response up to closing `OK` or `ACK` line
+        async fn response(&mut self) -> Vec<String> {
+            let mut response = Vec::new();
+            while let Some(line) = self.lines.next_line().await.unwrap() {
+                let done = line.starts_with("OK") || line.starts_with("ACK");
+                response.push(line);
+                if done {
+                    break;
+                }
+            }
+            response
+        }
+    }
+
+    #[tokio::test]
+    async fn greets_and_answers_commands() {
+        let (mut client, greeting) = Client::connect(stub_server().await).await;
+        assert_eq!(greeting, GREETING.trim_end());
+        client.send("status\n").await;
+        assert_eq!(
+            client.response().await,
+            ["volume: 100", "state: stop", "OK"]
+        );
+        client.send("nonsense 1\n").await;
+        assert_eq!(
+            client.response().await,
+            ["ACK [5@0] {nonsense} unknown command \"nonsense\""]
+        );
+    }
+
+    #[tokio::test]
+    async fn runs_command_lists() {
+        let (mut client, _) = Client::connect(stub_server().await).await;
+        client
+            .send("command_list_ok_begin\nping\nstatus\ncommand_list_end\n")
+            .await;
+        assert_eq!(
+            client.response().await,
+            ["list_OK", "volume: 100", "state: stop", "list_OK", "OK"]
+        );
+        client
+            .send("command_list_begin\nping\nbad\nstatus\ncommand_list_end\n")
+            .await;
+        assert_eq!(
+            client.response().await,
+            ["ACK [5@1] {bad} unknown command \"bad\""]
+        );
+        client.send("ping\n").await;
+        assert_eq!(client.response().await, ["OK"]);
+    }
+
+    #[tokio::test]
+    async fn idle_reports_changes_and_ends_with_noidle() {
+        let address = stub_server().await;
+        let (mut idler, _) = Client::connect(address).await;
+        idler.send("idle\nnoidle\n").await;
+        assert_eq!(idler.response().await, ["OK"]);
+
+        idler.send("idle mixer player\n").await;
+        let (mut other, _) = Client::connect(address).await;
+        other.send("play\n").await;
+        assert_eq!(other.response().await, ["OK"]);
+        assert_eq!(idler.response().await, ["changed: player", "OK"]);
+    }
+
+    #[tokio::test]
+    async fn close_ends_connection() {
+        let (mut client, _) = Client::connect(stub_server().await).await;
+        client.send("close\n").await;
+        let mut rest = String::new();
+        client
+            .lines
+            .get_mut()
+            .read_to_string(&mut rest)
+            .await
+            .unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn parses_song_ranges() {
+        assert_eq!(parse_range("2", 5).ok(), Some(2..3));
+        assert_eq!(parse_range("1:3", 5).ok(), Some(1..3));
+        assert_eq!(parse_range("3:", 5).ok(), Some(3..5));
+        assert_eq!(parse_range("0:5", 5).ok(), Some(0..5));
+        assert!(parse_range("5", 5).is_err());
+        assert!(parse_range("3:1", 5).is_err());
+        assert!(parse_range("1:6", 5).is_err());
+        assert!(parse_range("x", 5).is_err());
+        assert!(parse_range("-1", 5).is_err());
+    }
+
+    #[test]
+    fn song_uris_are_relative_to_playlists_dir() {
+        let dir = Path::new("/home/u/.yta-cli/playlists");
+        let item = QueueItem::new("/home/u/.yta-cli/playlists/mix/a.mp3".into(), "a".into());
+        assert_eq!(song_uri(dir, &item), "mix/a.mp3");
+        let item = QueueItem::new("/music/b.mp3".into(), "b".into());
+        assert_eq!(song_uri(dir, &item), "/music/b.mp3");
+    }
+}
@@ -0,0 +1,333 @@
+use std::{
+    fmt::{self, Display},
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use hound::{SampleFormat, WavSpec, WavWriter};
+
+use rodio::{
+    ChannelCount, Device, DeviceTrait, OutputStream, OutputStreamBuilder, SampleRate, Source,
+    cpal::{self, traits::HostTrait},
+    queue::SourcesQueueOutput,
+    source::UniformSourceIterator,
+};
+
+use crate::error::Error;
+
+///Format audio gets rendered in when there is no sound card involved
+const RENDER_CHANNELS: ChannelCount = 2;
+const RENDER_SAMPLE_RATE: SampleRate = 44100;
+///Frames rendered between checks whether rendering is ahead of time
+const RENDER_CHUNK: usize = 441;
+
+///Where playback goes
+#[derive(Clone, Debug, PartialEq)]
+pub enum OutputTarget {
+    ///Sound card, `None` being default one
+    Device(Option<String>),
+    ///Discards audio, `speed` times faster than real time
+    Null { speed: f32 },
+    ///Writes audio to WAV file as fast as it would be played
+    Wav(PathBuf),
+}
+impl Display for OutputTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputTarget::Device(Some(name)) => write!(f, "device {name}"),
+            OutputTarget::Device(None) => write!(f, "default device"),
+            OutputTarget::Null { speed } => write!(f, "null sink at {speed}x"),
+            OutputTarget::Wav(path) => write!(f, "WAV file {}", path.display()),
+        }
+    }
+}
+
+pub struct DeviceInfo {
+    pub name: String,
+    pub is_default: bool,
+}
+
+pub fn list_output_devices() -> Result<Vec<DeviceInfo>, Error> {
+    let host = cpal::default_host();
+    let default_name = host.default_output_device().and_then(|d| d.name().ok());
+    let devices = host
+        .output_devices()?
+        .filter_map(|d| d.name().ok())
+        .map(|name| DeviceInfo {
+            is_default: Some(&name) == default_name.as_ref(),
+            name,
+        })
+        .collect();
+    Ok(devices)
+}
+
+///Finds device by exact name, falling back to case-insensitive partial match
+pub fn find_output_device(name: &str) -> Result<Device, Error> {
+    let host = cpal::default_host();
+    let devices: Vec<Device> = host.output_devices()?.collect();
+    let names: Vec<Option<String>> = devices.iter().map(|d| d.name().ok()).collect();
+    match match_device_name(&names, name) {
+        Some(i) => Ok(devices.into_iter().nth(i).unwrap()),
+        None => Err(Error::DeviceNotFound(name.to_string())),
+    }
+}
+
+///Position of `name` among names of devices, `None` being device whose name can't be read
+fn match_device_name(names: &[Option<String>], name: &str) -> Option<usize> {
+    let lower = name.to_lowercase();
+    names
+        .iter()
+        .position(|n| n.as_deref() == Some(name))
+        .or_else(|| {
+            names.iter().position(|n| {
+                n.as_ref()
+                    .is_some_and(|n| n.to_lowercase().contains(&lower))
+            })
+        })
+}
+
+fn open_stream(device: Option<&str>) -> Result<OutputStream, Error> {
+    let mut stream = match device {
+        Some(name) => OutputStreamBuilder::from_device(find_output_device(name)?)?
+            .open_stream_or_fallback()?,
+        None => OutputStreamBuilder::open_default_stream()?,
+    };
+    stream.log_on_drop(false);
+    Ok(stream)
+}
+
+///Audio coming out of player's sink, which can be moved between outputs without
+///interrupting playback
+#[derive(Clone)]
+pub struct SharedOutput {
+    queue: Arc<Mutex<SourcesQueueOutput>>,
+    active: Arc<AtomicU64>,
+}
+impl SharedOutput {
+    pub fn new(queue: SourcesQueueOutput) -> Self {
+        Self {
+            queue: Arc::new(Mutex::new(queue)),
+            active: Arc::new(AtomicU64::new(0)),
+        }
+    }
+    ///Ends every source created so far
+    fn deactivate(&self) {
+        self.active.fetch_add(1, Ordering::SeqCst);
+    }
+    ///Creates source reading sink's audio, every previously created one ends
+    pub fn source(&self) -> OutputSource {
+        OutputSource {
+            id: self.active.fetch_add(1, Ordering::SeqCst) + 1,
+            shared: self.clone(),
+        }
+    }
+}
+
+pub struct OutputSource {
+    shared: SharedOutput,
+    id: u64,
+}
+impl Iterator for OutputSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.shared.active.load(Ordering::Relaxed) != self.id {
+            return None;
+        }
+        self.shared.queue.lock().unwrap().next()
+    }
+}
+impl Source for OutputSource {
+    fn current_span_len(&self) -> Option<usize> {
+        self.shared.queue.lock().unwrap().current_span_len()
+    }
+    fn channels(&self) -> ChannelCount {
+        self.shared.queue.lock().unwrap().channels()
+    }
+    fn sample_rate(&self) -> SampleRate {
+        self.shared.queue.lock().unwrap().sample_rate()
+    }
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+enum Backend {
+    ///Stream stops playing once dropped
+    Stream { _stream: OutputStream },
+    ///Audio is pulled by render thread, which ends together with its source
+    Render {
+        thread: RenderThread,
+        ///File thread writes to
+        wav: Option<PathBuf>,
+    },
+}
+impl Backend {
+    ///Waits until audio stopped, source has to be ended already
+    async fn stop(mut self) {
+        if let Backend::Render { thread, .. } = &mut self {
+            thread.join().await;
+        }
+    }
+}
+
+///Joins render thread once dropped, so WAV file is complete before process can exit
+struct RenderThread(Option<JoinHandle<()>>);
+impl RenderThread {
+    ///Joins without blocking runtime's worker, thread ends within a chunk once its source does
+    async fn join(&mut self) {
+        if let Some(thread) = self.0.take() {
+            let _ = tokio::task::spawn_blocking(move || thread.join()).await;
+        }
+    }
+}
+impl Drop for RenderThread {
+    fn drop(&mut self) {
+        if let Some(thread) = self.0.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+///Output currently playing `SharedOutput`
+pub struct AudioOutput {
+    shared: SharedOutput,
+    backend: Backend,
+}
+impl AudioOutput {
+    pub fn open(target: &OutputTarget, shared: SharedOutput) -> Result<Self, Error> {
+        let backend = start_backend(target, &shared)?;
+        Ok(Self { shared, backend })
+    }
+    ///Moves playback to another output, current one keeps playing if that fails
+    pub async fn switch(&mut self, target: &OutputTarget) -> Result<(), Error> {
+        //Creating file would truncate what current output is writing
+        if let (
+            OutputTarget::Wav(path),
+            Backend::Render {
+                wav: Some(current), ..
+            },
+        ) = (target, &self.backend)
+            && is_same_file(path, current)
+        {
+            return Err(Error::WavInUse(current.clone()));
+        }
+        let backend = start_backend(target, &self.shared)?;
+        std::mem::replace(&mut self.backend, backend).stop().await;
+        Ok(())
+    }
+    ///Stops playback, waiting until WAV file is complete
+    pub async fn close(mut self) {
+        self.shared.deactivate();
+        if let Backend::Render { thread, .. } = &mut self.backend {
+            thread.join().await;
+        }
+    }
+}
+impl Drop for AudioOutput {
+    fn drop(&mut self) {
+        if let Backend::Render { .. } = self.backend {
+            self.shared.deactivate();
+        }
+    }
+}
+
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn start_backend(target: &OutputTarget, shared: &SharedOutput) -> Result<Backend, Error> {
+    match target {
+        OutputTarget::Device(device) => {
+            let stream = open_stream(device.as_deref())?;
+            stream.mixer().add(shared.source());
+            Ok(Backend::Stream { _stream: stream })
+        }
+        OutputTarget::Null { speed } => {
+            let (source, speed) = (shared.source(), *speed);
+            let thread = thread::spawn(move || render(source, speed, None));
+            Ok(Backend::Render {
+                thread: RenderThread(Some(thread)),
+                wav: None,
+            })
+        }
+        OutputTarget::Wav(path) => {
+            let spec = WavSpec {
+                channels: RENDER_CHANNELS,
+                sample_rate: RENDER_SAMPLE_RATE,
+                bits_per_sample: 32,
+                sample_format: SampleFormat::Float,
+            };
+            let writer = WavWriter::create(path, spec)?;
+            let source = shared.source();
+            let thread = thread::spawn(move || render(source, 1.0, Some(writer)));
+            Ok(Backend::Render {
+                thread: RenderThread(Some(thread)),
+                wav: Some(path.clone()),
+            })
+        }
+    }
+}
+
+///Pulls audio at `speed` times real time until source ends
+fn render(source: OutputSource, speed: f32, mut writer: Option<WavWriter<BufWriter<File>>>) {
+    let mut source = UniformSourceIterator::new(source, RENDER_CHANNELS, RENDER_SAMPLE_RATE);
+    let started = Instant::now();
+    let mut frames = 0u64;
+
+    loop {
+        for _ in 0..RENDER_CHUNK * RENDER_CHANNELS as usize {
+            let Some(sample) = source.next() else {
+                if let Some(writer) = writer
+                    && let Err(e) = writer.finalize()
+                {
+                    say!("Failed to finish WAV file: {e}");
+                }
+                return;
+            };
+            if let Some(w) = writer.as_mut()
+                && let Err(e) = w.write_sample(sample)
+            {
+                say!("Failed to write WAV file: {e}");
+                writer = None;
+            }
+        }
+        frames += RENDER_CHUNK as u64;
+        let target =
+            Duration::from_secs_f64(frames as f64 / RENDER_SAMPLE_RATE as f64 / speed as f64);
+        if let Some(wait) = target.checked_sub(started.elapsed()) {
+            thread::sleep(wait);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_device_names() {
+        let names = [
+            None,
+            Some("USB Audio".to_string()),
+            Some("HDMI".to_string()),
+            Some("hdmi".to_string()),
+        ];
+        assert_eq!(match_device_name(&names, "hdmi"), Some(3));
+        assert_eq!(match_device_name(&names, "HDMI"), Some(2));
+        assert_eq!(match_device_name(&names, "usb"), Some(1));
+        assert_eq!(match_device_name(&names, "Hdm"), Some(2));
+        assert_eq!(match_device_name(&names, "speaker"), None);
+        assert_eq!(match_device_name(&[], "hdmi"), None);
+    }
+}
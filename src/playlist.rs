@@ -1,105 +1,359 @@
-use rodio::{Decoder, OutputStreamBuilder, Sink, Source, source::SeekError};
-use std::{io::Cursor, sync::Arc, time::Duration};
+use rodio::{Decoder, Sink, Source, source::SeekError};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{self, Display},
+    io::Cursor,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 use tokio::{
     fs,
-    sync::{Mutex, Notify, mpsc::Receiver},
+    sync::{Mutex, Notify, mpsc::Receiver, oneshot, watch},
     time::Instant,
 };
 
-use crate::queue::Queue;
+use crate::{
+    config::{Config, Normalization, SleepAction},
+    effects::{PositionHandle, SharedF32, TimeStretch, TrackedPosition},
+    equalizer::{EqHandle, EqSettings, Equalizer},
+    error::Error,
+    output::{AudioOutput, OutputTarget, SharedOutput},
+    queue::{Queue, QueueItem},
+    utils::{format_duration, parse_duration},
+};
+
+const SLEEP_TICK: Duration = Duration::from_millis(100);
+const POSITION_TICK: Duration = Duration::from_millis(50);
 
 pub enum PlaylistControl {
     Play,
     Pause,
+    TogglePause,
     Skip,
     Previous,
-    SkipBy(u64),
+    ///Seeks by seconds, backwards when negative
+    SkipBy(i64),
+    ///Seeks to position in current audio
+    Seek(Duration),
+    ///Arms sleep timer, `None` cancels it
+    Sleep(Option<SleepTimer>),
+    ///Repeats section between two positions of current audio, `None` turns it off
+    Loop(Option<(Duration, Duration)>),
+    ///Changes playback speed, either through sink or by time stretching which keeps pitch
+    Speed {
+        factor: f32,
+        keep_pitch: bool,
+    },
+    Eq(EqSettings),
+    ///Moves playback to another device or backend
+    Output {
+        target: OutputTarget,
+        reply: oneshot::Sender<Result<(), Error>>,
+    },
+    Status(oneshot::Sender<PlaybackStatus>),
+    ///Tracks playing now, including enqueued ones
+    Queue(oneshot::Sender<Vec<QueueItem>>),
+    ///Appends audio to what's playing, without changing playlist
+    Enqueue(QueueItem),
+    ///Sets volume, 1.0 is unchanged
+    Volume(f32),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum SleepTimer {
+    After(Duration),
+    EndOfTrack,
 }
+impl FromStr for SleepTimer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "end-of-track" | "eot" => Ok(SleepTimer::EndOfTrack),
+            _ => match parse_duration(s) {
+                Ok(d) if Instant::now().checked_add(d).is_some() => Ok(SleepTimer::After(d)),
+                Ok(_) => Err(format!("sleep duration is too long: {s}")),
+                Err(e) => Err(e.to_string()),
+            },
+        }
+    }
+}
+impl Display for SleepTimer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SleepTimer::After(d) => write!(f, "{}", format_duration(*d)),
+            SleepTimer::EndOfTrack => write!(f, "end of track"),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 enum PlaybackState {
     Playing,
     Paused,
+    Stopped,
 }
 
+#[derive(Clone)]
+struct NowPlaying {
+    index: usize,
+    name: String,
+    duration: Option<Duration>,
+    end: Option<Duration>,
+}
+impl NowPlaying {
+    ///Position at which audio stops playing, taking end trim into account
+    fn end(&self) -> Option<Duration> {
+        self.end.or(self.duration)
+    }
+}
+
+pub enum SleepStatus {
+    Remaining(Duration),
+    EndOfTrack,
+    FadingOut,
+}
+
+pub struct PlaybackStatus {
+    pub playlist: String,
+    pub index: usize,
+    pub track: String,
+    pub position: Duration,
+    pub duration: Option<Duration>,
+    pub paused: bool,
+    pub sleep: Option<SleepStatus>,
+    pub ab_loop: Option<(Duration, Duration)>,
+    pub speed: f32,
+    pub volume: f32,
+}
+impl Display for PlaybackStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = if self.paused { "paused" } else { "playing" };
+        write!(
+            f,
+            "[{state}] {}. {} {}",
+            self.index + 1,
+            self.track,
+            format_duration(self.position)
+        )?;
+        if let Some(duration) = self.duration {
+            write!(f, " / {}", format_duration(duration))?;
+        }
+        write!(f, " | playlist: {}", self.playlist)?;
+        if self.speed != 1.0 {
+            write!(f, " | speed {:.2}x", self.speed)?;
+        }
+        if self.volume != 1.0 {
+            write!(f, " | volume {:.0}%", self.volume * 100.0)?;
+        }
+        if let Some((a, b)) = self.ab_loop {
+            write!(f, " | loop {}-{}", format_duration(a), format_duration(b))?;
+        }
+        match &self.sleep {
+            Some(SleepStatus::Remaining(d)) => write!(f, " | sleep in {}", format_duration(*d)),
+            Some(SleepStatus::EndOfTrack) => write!(f, " | sleep at end of track"),
+            Some(SleepStatus::FadingOut) => write!(f, " | sleep: fading out"),
+            None => Ok(()),
+        }
+    }
+}
+
+///Track and pause state shown in REPL prompt, sent whenever one of them changes
+#[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
+pub struct NowPlayingInfo {
+    pub playlist: String,
+    pub index: usize,
+    pub track: String,
+    pub paused: bool,
+}
+
+///State shared between playing loop and control task
+#[derive(Clone)]
+struct Player {
+    ///Tracks of playlist followed by enqueued ones
+    queue: Arc<Mutex<Vec<QueueItem>>>,
+    current_index: Arc<Mutex<usize>>,
+    notify: Arc<Notify>,
+    state: Arc<Mutex<PlaybackState>>,
+    sink: Arc<Mutex<Sink>>,
+    now_playing: Arc<Mutex<Option<NowPlaying>>>,
+    ab_loop: Arc<Mutex<Option<(Duration, Duration)>>>,
+    position: PositionHandle,
+    ///Tempo of time stretch applied before sink
+    stretch: SharedF32,
+    eq: EqHandle,
+    playlist: String,
+    published: watch::Sender<Option<NowPlayingInfo>>,
+}
+
+///Plays playlist starting from track at `start_index`, counted from 0
 pub async fn play_playlist(
     playlist_name: &str,
+    start_index: usize,
     rx: Receiver<PlaylistControl>,
+    published: watch::Sender<Option<NowPlayingInfo>>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let queue = Queue::from_queue_json(playlist_name).await?;
-    let current_index = Arc::new(Mutex::new(0usize));
-    let stream_handle = OutputStreamBuilder::open_default_stream()?;
-    let sink = rodio::Sink::connect_new(&stream_handle.mixer());
-
-    let sink = Arc::new(Mutex::new(sink));
-    let notify = Arc::new(Notify::new());
+    play_items(playlist_name, queue.items, start_index, rx, published).await
+}
 
-    let index_clone = Arc::clone(&current_index);
-    let sink_clone = Arc::clone(&sink);
-    let notify_clone = Arc::clone(&notify);
+///Plays tracks which don't have to come from one playlist, `name` is shown as their playlist
+pub async fn play_items(
+    playlist_name: &str,
+    items: Vec<QueueItem>,
+    start_index: usize,
+    rx: Receiver<PlaylistControl>,
+    published: watch::Sender<Option<NowPlayingInfo>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if start_index > 0 && start_index >= items.len() {
+        return Err(format!("playlist has only {} tracks", items.len()).into());
+    }
+    let config = Config::load().await?;
+    let (sink, sink_output) = Sink::new();
+    let output = AudioOutput::open(&config.output_target(), SharedOutput::new(sink_output))?;
+    let output = Arc::new(Mutex::new(output));
 
-    let state = Arc::new(Mutex::new(PlaybackState::Playing));
-    let state_clone = Arc::clone(&state);
+    let player = Player {
+        queue: Arc::new(Mutex::new(items)),
+        current_index: Arc::new(Mutex::new(start_index)),
+        notify: Arc::new(Notify::new()),
+        state: Arc::new(Mutex::new(PlaybackState::Playing)),
+        sink: Arc::new(Mutex::new(sink)),
+        now_playing: Arc::new(Mutex::new(None)),
+        ab_loop: Arc::new(Mutex::new(None)),
+        position: PositionHandle::default(),
+        stretch: SharedF32::new(1.0),
+        eq: EqHandle::new(config.eq),
+        playlist: playlist_name.to_string(),
+        published,
+    };
 
-    tokio::spawn(async move {
-        let _ = control_playlist(rx, index_clone, notify_clone, state_clone, sink_clone).await;
+    let player_clone = player.clone();
+    let name = playlist_name.to_string();
+    let control_config = config.clone();
+    let control_output = output.clone();
+    let control = tokio::spawn(async move {
+        control_playlist(rx, player_clone, name, control_config, control_output).await;
     });
 
+    // error is turned into text, as it has to be held across awaiting control task
+    let result = play_queue(&player, &config)
+        .await
+        .map_err(|e| e.to_string());
+    control.abort();
+    let _ = control.await;
+    // output has to be closed before process exits, which is only after playback returns
+    if let Some(output) = Arc::into_inner(output) {
+        output.into_inner().close().await;
+    }
+    player.published.send_replace(None);
+    Ok(result?)
+}
+
+async fn play_queue(player: &Player, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let Player {
+        current_index,
+        notify,
+        state,
+        sink,
+        now_playing,
+        ab_loop,
+        position,
+        stretch,
+        eq,
+        ..
+    } = player;
+
     loop {
         let idx = *current_index.lock().await;
-        if idx >= queue.items.len() {
+        let Some(item) = player.queue.lock().await.get(idx).cloned() else {
             break;
-        }
+        };
 
-        let audio_bytes = fs::read(&queue.items[idx].file_path).await?;
-        let cursor = Cursor::new(audio_bytes);
-        let source = Decoder::try_from(cursor)?.stoppable();
-        let duration = source.total_duration().unwrap_or(Duration::from_secs(5));
+        let audio_bytes = fs::read(&item.file_path).await?;
+        let byte_len = audio_bytes.len() as u64;
+        // without seekable flag decoder can't seek backwards, which A-B loop needs
+        let decoder = Decoder::builder()
+            .with_data(Cursor::new(audio_bytes))
+            .with_byte_len(byte_len)
+            .with_seekable(true)
+            .build()?;
+        let duration = decoder.total_duration();
+        let gain = match (config.normalization, item.replay_gain) {
+            (Normalization::Off, _) | (_, None) => 1.0,
+            (mode, Some(rg)) => rg.factor(config.target_lufs, mode == Normalization::Album),
+        };
+        let source = TimeStretch::new(
+            Equalizer::new(
+                TrackedPosition::new(decoder, position.clone()).amplify(gain),
+                eq.clone(),
+            ),
+            stretch.clone(),
+        );
+
+        *now_playing.lock().await = Some(NowPlaying {
+            index: idx,
+            name: item.name.clone(),
+            duration,
+            end: item.end_offset(),
+        });
+        *ab_loop.lock().await = None;
+        player.publish().await;
 
         {
             let sink_lock = sink.lock().await;
             sink_lock.clear();
             sink_lock.append(source);
+            if let Some(start) = item.start_offset() {
+                let _ = seek(&sink_lock, start);
+            }
             sink_lock.play();
         }
 
-        let mut elapsed = Duration::ZERO;
-        let mut remaining = duration;
-        let mut started = Instant::now();
+        let mut ticker = tokio::time::interval(POSITION_TICK);
 
         loop {
             tokio::select! {
-                _ = tokio::time::sleep(remaining) => {
-                    *current_index.lock().await += 1;
-                    break;
+                _ = ticker.tick() => {
+                    if player.track_finished(item.end_offset()).await {
+                        *current_index.lock().await += 1;
+                        break;
+                    }
                 }
                 _ = notify.notified() => {
                     let current_state = state.lock().await.clone();
 
                     match current_state {
                         PlaybackState::Paused => {
-                            elapsed += started.elapsed();
                             {
                                 sink.lock().await.pause();
                             }
 
                             loop {
                                 notify.notified().await;
-                                if let PlaybackState::Playing = *state.lock().await {
-                                    break;
+                                match *state.lock().await {
+                                    PlaybackState::Playing => break,
+                                    PlaybackState::Stopped => {
+                                        sink.lock().await.stop();
+                                        return Ok(());
+                                    }
+                                    PlaybackState::Paused => {}
                                 }
                             }
 
                             {
                                 sink.lock().await.play();
                             }
-
-                            remaining = duration.checked_sub(elapsed).unwrap_or(Duration::from_secs(1));
-                            started = Instant::now();
                             continue;
                         }
                         PlaybackState::Playing => {
                             break;
                         }
+                        PlaybackState::Stopped => {
+                            sink.lock().await.stop();
+                            return Ok(());
+                        }
                     }
                 }
             }
@@ -110,40 +364,282 @@ pub async fn play_playlist(
 }
 async fn control_playlist(
     mut rx: Receiver<PlaylistControl>,
-    current_index: Arc<Mutex<usize>>,
-    notify: Arc<Notify>,
-    state: Arc<Mutex<PlaybackState>>,
-    sink: Arc<Mutex<Sink>>,
-) -> Result<(), SeekError> {
-    while let Some(msg) = rx.recv().await {
-        match msg {
-            PlaylistControl::Pause => {
-                *state.lock().await = PlaybackState::Paused;
-                notify.notify_one();
+    player: Player,
+    playlist_name: String,
+    config: Config,
+    output: Arc<Mutex<AudioOutput>>,
+) {
+    let mut sleep: Option<SleepState> = None;
+    let mut ticker = tokio::time::interval(SLEEP_TICK);
+
+    loop {
+        tokio::select! {
+            msg = rx.recv() => {
+                let Some(msg) = msg else {
+                    break;
+                };
+                match msg {
+                    PlaylistControl::Pause => player.set_paused(true).await,
+                    PlaylistControl::Play => player.set_paused(false).await,
+                    PlaylistControl::TogglePause => {
+                        let playing = matches!(*player.state.lock().await, PlaybackState::Playing);
+                        player.set_paused(playing).await;
+                    }
+                    PlaylistControl::Skip => {
+                        *player.current_index.lock().await += 1;
+                        player.notify.notify_one();
+                    }
+                    PlaylistControl::Previous => {
+                        let mut idx = player.current_index.lock().await;
+                        if *idx > 0 {
+                            *idx -= 1;
+                        }
+                        player.notify.notify_one();
+                    }
+
+                    PlaylistControl::SkipBy(v) => {
+                        let locked = player.sink.lock().await;
+                        let current_duration = player.position.get();
+                        let offset = Duration::from_secs(v.unsigned_abs());
+                        let next_duration = if v < 0 {
+                            current_duration.saturating_sub(offset)
+                        } else {
+                            current_duration + offset
+                        };
+                        // fails past end of audio with unknown duration, which is not worth stopping control for
+                        let _ = seek(&locked, next_duration);
+                    }
+                    PlaylistControl::Seek(position) => {
+                        let _ = seek(&*player.sink.lock().await, position);
+                    }
+                    PlaylistControl::Sleep(timer) => {
+                        if let Some(previous) = sleep.take() {
+                            previous.cancel(&player).await;
+                        }
+                        let track = *player.current_index.lock().await;
+                        sleep = timer.and_then(|timer| SleepState::new(timer, track));
+                    }
+                    PlaylistControl::Loop(section) => {
+                        *player.ab_loop.lock().await = section;
+                        if let Some((a, _)) = section {
+                            let locked = player.sink.lock().await;
+                            if player.position.get() < a {
+                                let _ = seek(&locked, a);
+                            }
+                        }
+                    }
+                    PlaylistControl::Speed { factor, keep_pitch } => {
+                        let locked = player.sink.lock().await;
+                        if keep_pitch {
+                            locked.set_speed(1.0);
+                            player.stretch.set(factor);
+                        } else {
+                            player.stretch.set(1.0);
+                            locked.set_speed(factor);
+                        }
+                    }
+                    PlaylistControl::Eq(settings) => {
+                        player.eq.set(settings);
+                    }
+                    PlaylistControl::Output { target, reply } => {
+                        let _ = reply.send(output.lock().await.switch(&target).await);
+                    }
+                    PlaylistControl::Enqueue(item) => {
+                        player.queue.lock().await.push(item);
+                    }
+                    PlaylistControl::Volume(volume) => {
+                        player.sink.lock().await.set_volume(volume);
+                    }
+                    PlaylistControl::Queue(reply) => {
+                        let _ = reply.send(player.queue.lock().await.clone());
+                    }
+                    PlaylistControl::Status(reply) => {
+                        let _ = reply.send(player.status(&playlist_name, sleep.as_ref()).await);
+                    }
+                }
             }
-            PlaylistControl::Play => {
-                *state.lock().await = PlaybackState::Playing;
-                notify.notify_one();
+            _ = ticker.tick(), if sleep.is_some() => {
+                if let Some(state) = sleep.as_mut()
+                    && state.tick(&player, &config).await
+                {
+                    sleep = None;
+                }
+            }
+        }
+    }
+}
+
+impl Player {
+    ///How many seconds of audio play per second
+    fn speed(&self, sink: &Sink) -> f32 {
+        sink.speed() * self.stretch.get()
+    }
+    ///Jumps back to start of A-B loop when needed, returns true once current audio is over
+    async fn track_finished(&self, end: Option<Duration>) -> bool {
+        let sink = self.sink.lock().await;
+        let pos = self.position.get();
+        if let Some((a, b)) = *self.ab_loop.lock().await
+            && pos >= b
+        {
+            let _ = seek(&sink, a);
+            return false;
+        }
+        sink.empty() || end.is_some_and(|end| pos >= end)
+    }
+    ///Wakes playing loop only when state changes, as waking it while playing restarts audio
+    async fn set_paused(&self, paused: bool) {
+        let mut state = self.state.lock().await;
+        if matches!(*state, PlaybackState::Paused) == paused {
+            return;
+        }
+        *state = if paused {
+            PlaybackState::Paused
+        } else {
+            PlaybackState::Playing
+        };
+        drop(state);
+        self.notify.notify_one();
+        self.publish().await;
+    }
+    async fn publish(&self) {
+        let Some(now_playing) = self.now_playing.lock().await.clone() else {
+            return;
+        };
+        let paused = !matches!(*self.state.lock().await, PlaybackState::Playing);
+        self.published.send_replace(Some(NowPlayingInfo {
+            playlist: self.playlist.clone(),
+            index: now_playing.index,
+            track: now_playing.name,
+            paused,
+        }));
+    }
+    async fn status(&self, playlist_name: &str, sleep: Option<&SleepState>) -> PlaybackStatus {
+        let (speed, volume) = {
+            let sink = self.sink.lock().await;
+            (self.speed(&sink), sink.volume())
+        };
+        let now_playing = self.now_playing.lock().await.clone();
+        let paused = !matches!(*self.state.lock().await, PlaybackState::Playing);
+        let (index, track, duration) = match now_playing {
+            Some(v) => (v.index, v.name, v.duration),
+            None => (*self.current_index.lock().await, String::new(), None),
+        };
+
+        PlaybackStatus {
+            playlist: playlist_name.to_string(),
+            index,
+            track,
+            position: self.position.get(),
+            duration,
+            paused,
+            sleep: sleep.map(SleepState::status),
+            ab_loop: *self.ab_loop.lock().await,
+            speed,
+            volume,
+        }
+    }
+}
+
+///Seeks current audio, sink scales seek position by its speed so it gets undone here
+fn seek(sink: &Sink, pos: Duration) -> Result<(), SeekError> {
+    sink.try_seek(pos.div_f32(sink.speed()))
+}
+
+struct SleepState {
+    timer: SleepTimer,
+    deadline: Instant,
+    ///When fade out started and volume from before it
+    fade: Option<(Instant, f32)>,
+    ///Index of track playing when timer got armed, end of track means end of this one
+    track: usize,
+}
+impl SleepState {
+    ///None when the deadline is too far away to be represented
+    fn new(timer: SleepTimer, track: usize) -> Option<Self> {
+        let deadline = match timer {
+            SleepTimer::After(d) => Instant::now().checked_add(d)?,
+            SleepTimer::EndOfTrack => Instant::now(),
+        };
+        Some(Self {
+            timer,
+            deadline,
+            fade: None,
+            track,
+        })
+    }
+    fn status(&self) -> SleepStatus {
+        match (self.fade, self.timer) {
+            (Some(_), _) => SleepStatus::FadingOut,
+            (None, SleepTimer::After(_)) => {
+                SleepStatus::Remaining(self.deadline.saturating_duration_since(Instant::now()))
             }
-            PlaylistControl::Skip => {
-                *current_index.lock().await += 1;
-                notify.notify_one();
+            (None, SleepTimer::EndOfTrack) => SleepStatus::EndOfTrack,
+        }
+    }
+    async fn should_fire(&self, player: &Player, sink: &Sink, fade: Duration) -> bool {
+        match self.timer {
+            SleepTimer::After(_) => Instant::now() >= self.deadline,
+            SleepTimer::EndOfTrack => {
+                if !matches!(*player.state.lock().await, PlaybackState::Playing) {
+                    return false;
+                }
+                let Some(end) = player
+                    .now_playing
+                    .lock()
+                    .await
+                    .as_ref()
+                    .filter(|playing| playing.index == self.track)
+                    .and_then(NowPlaying::end)
+                else {
+                    return false;
+                };
+                end.saturating_sub(player.position.get())
+                    .div_f32(player.speed(sink))
+                    <= fade
             }
-            PlaylistControl::Previous => {
-                let mut idx = current_index.lock().await;
-                if *idx > 0 {
-                    *idx -= 1;
+        }
+    }
+    ///Advances timer, returns true once playback got paused or stopped
+    async fn tick(&mut self, player: &Player, config: &Config) -> bool {
+        let fade = config.sleep_fade();
+        let sink = player.sink.lock().await;
+        //Track ended between ticks, rest of fade must not play over the next one
+        let track_over = matches!(self.timer, SleepTimer::EndOfTrack)
+            && *player.current_index.lock().await != self.track;
+        let (fade_started, volume) = match self.fade {
+            Some(fade) => fade,
+            None if track_over => (Instant::now(), sink.volume()),
+            None => {
+                if self.should_fire(player, &sink, fade).await {
+                    self.fade = Some((Instant::now(), sink.volume()));
                 }
-                notify.notify_one();
+                return false;
             }
+        };
 
-            PlaylistControl::SkipBy(v) => {
-                let locked = sink.lock().await;
-                let current_duration = locked.get_pos();
-                let next_duration = current_duration + Duration::from_secs(v);
-                locked.try_seek(next_duration)?;
-            }
+        let progress = if fade.is_zero() || track_over {
+            1.0
+        } else {
+            fade_started.elapsed().as_secs_f32() / fade.as_secs_f32()
+        };
+        if progress < 1.0 {
+            sink.set_volume(volume * (1.0 - progress));
+            return false;
+        }
+
+        sink.pause();
+        sink.set_volume(volume);
+        *player.state.lock().await = match config.sleep_action {
+            SleepAction::Pause => PlaybackState::Paused,
+            SleepAction::Stop => PlaybackState::Stopped,
+        };
+        player.notify.notify_one();
+        player.publish().await;
+        true
+    }
+    async fn cancel(self, player: &Player) {
+        if let Some((_, volume)) = self.fade {
+            player.sink.lock().await.set_volume(volume);
         }
     }
-    Ok(())
 }
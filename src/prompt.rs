@@ -0,0 +1,82 @@
+use std::borrow::Cow;
+
+use reedline::{DefaultPrompt, DefaultPromptSegment, Prompt, PromptEditMode, PromptHistorySearch};
+use tokio::sync::watch;
+
+use crate::{download::DownloadJobs, playlist::NowPlayingInfo};
+
+///Longer track names get cut so prompt leaves room for typing
+const TRACK_WIDTH: usize = 24;
+
+///Prompt showing current track, whether it's paused and how many downloads run
+pub struct ReplPrompt {
+    player: watch::Receiver<Option<NowPlayingInfo>>,
+    downloads: DownloadJobs,
+    ///Provides indicators, only left part is replaced
+    default: DefaultPrompt,
+}
+impl ReplPrompt {
+    pub fn new(player: watch::Receiver<Option<NowPlayingInfo>>, downloads: DownloadJobs) -> Self {
+        Self {
+            player,
+            downloads,
+            default: DefaultPrompt::new(
+                DefaultPromptSegment::Basic("yta-cli".into()),
+                DefaultPromptSegment::Empty,
+            ),
+        }
+    }
+}
+
+fn truncate(text: &str, width: usize) -> String {
+    if text.chars().count() <= width {
+        return text.to_string();
+    }
+    let mut cut: String = text.chars().take(width - 1).collect();
+    cut.push('…');
+    cut
+}
+
+impl Prompt for ReplPrompt {
+    fn render_prompt_left(&self) -> Cow<'_, str> {
+        let mut prompt = String::from("yta-cli");
+        if let Some(info) = &*self.player.borrow() {
+            let state = if info.paused { "⏸" } else { "▶" };
+            prompt.push_str(&format!(" {state} {}", truncate(&info.track, TRACK_WIDTH)));
+        }
+        let downloads = self.downloads.active();
+        if downloads > 0 {
+            prompt.push_str(&format!(" ⇣{downloads}"));
+        }
+        prompt.into()
+    }
+    fn render_prompt_right(&self) -> Cow<'_, str> {
+        self.default.render_prompt_right()
+    }
+    fn render_prompt_indicator(&self, edit_mode: PromptEditMode) -> Cow<'_, str> {
+        self.default.render_prompt_indicator(edit_mode)
+    }
+    fn render_prompt_multiline_indicator(&self) -> Cow<'_, str> {
+        self.default.render_prompt_multiline_indicator()
+    }
+    fn render_prompt_history_search_indicator(
+        &self,
+        history_search: PromptHistorySearch,
+    ) -> Cow<'_, str> {
+        self.default
+            .render_prompt_history_search_indicator(history_search)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncates_by_characters() {
+        assert_eq!(truncate("Song", 4), "Song");
+        assert_eq!(truncate("Songs", 4), "Son…");
+        assert_eq!(truncate("Zażółć gęślą", 6), "Zażół…");
+        assert_eq!(truncate("", 3), "");
+    }
+}
@@ -1,11 +1,17 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, time::Duration};
 
 use serde::{Deserialize, Serialize};
-use tokio::{fs, io::AsyncReadExt, io::AsyncWriteExt};
+use tokio::{fs, io::AsyncReadExt, io::AsyncWriteExt, sync::Mutex};
 
-use crate::utils::get_playlists_dir;
+use crate::{
+    loudness::ReplayGain,
+    utils::{get_playlist_dir, playlist_dir},
+};
 
-#[derive(Deserialize, Serialize, Clone)]
+///Held by `Queue::update`, so edits of saved queues don't drop each other's changes
+static QUEUE_LOCK: Mutex<()> = Mutex::const_new(());
+
+#[derive(Deserialize, Serialize, Clone, Default)]
 pub struct Queue {
     pub items: Vec<QueueItem>,
 }
@@ -14,15 +20,55 @@ pub struct Queue {
 pub struct QueueItem {
     pub file_path: String,
     pub name: String,
+    ///Seconds skipped at the beginning of audio
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start: Option<f64>,
+    ///Seconds after which audio stops playing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end: Option<f64>,
+    ///Measured by `analyze`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replay_gain: Option<ReplayGain>,
+    ///Channel audio was downloaded from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uploader: Option<String>,
+}
+impl QueueItem {
+    pub fn new(file_path: String, name: String) -> Self {
+        Self {
+            file_path,
+            name,
+            start: None,
+            end: None,
+            replay_gain: None,
+            uploader: None,
+        }
+    }
+    ///Negative or non-finite offset from edited queue.json counts as missing
+    pub fn start_offset(&self) -> Option<Duration> {
+        self.start.and_then(|v| Duration::try_from_secs_f64(v).ok())
+    }
+    pub fn end_offset(&self) -> Option<Duration> {
+        self.end.and_then(|v| Duration::try_from_secs_f64(v).ok())
+    }
 }
 impl Queue {
     pub async fn from_queue_json(playlist_name: &str) -> Result<Self, std::io::Error> {
         let target_path = get_queue_path(playlist_name).await?;
-        let mut file = match fs::File::open(target_path).await {
+        let mut file = match fs::File::open(&target_path).await {
             Ok(v) => v,
             Err(_) => {
-                let queue = Queue { items: vec![] };
-                queue.to_json(playlist_name).await?;
+                let queue = Queue::default();
+                //Queue saved meanwhile by `update` must not get overwritten
+                let created = fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(&target_path)
+                    .await;
+                if let Ok(mut file) = created {
+                    file.write_all(&serde_json::to_vec_pretty(&queue)?).await?;
+                    file.flush().await?;
+                }
                 return Ok(queue);
             }
         };
@@ -30,11 +76,44 @@ impl Queue {
         file.read_to_end(&mut buf).await?;
         Ok(serde_json::from_slice(&buf)?)
     }
-    pub async fn to_json(&self, playlist_name: &str) -> Result<(), std::io::Error> {
+    ///Reads queue of playlist without creating anything, None when it has no queue yet
+    pub async fn read_queue_json(playlist_name: &str) -> Result<Option<Self>, std::io::Error> {
+        let target_path = playlist_dir(playlist_name)?.join("queue.json");
+        match fs::read(target_path).await {
+            Ok(buf) => Ok(Some(serde_json::from_slice(&buf)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+    ///Reads queue, lets `edit` change it and saves it unless `edit` fails, while no other
+    ///update of any queue runs
+    pub async fn update<T>(
+        playlist_name: &str,
+        edit: impl FnOnce(&mut Queue) -> Result<T, std::io::Error>,
+    ) -> Result<T, std::io::Error> {
+        let _guard = QUEUE_LOCK.lock().await;
+        let mut queue = Queue::from_queue_json(playlist_name).await?;
+        let result = edit(&mut queue)?;
+        queue.to_json(playlist_name).await?;
+        Ok(result)
+    }
+    pub async fn append_to_json(
+        playlist_name: &str,
+        item: QueueItem,
+    ) -> Result<(), std::io::Error> {
+        Queue::update(playlist_name, |queue| {
+            queue.items.push(item);
+            Ok(())
+        })
+        .await
+    }
+    async fn to_json(&self, playlist_name: &str) -> Result<(), std::io::Error> {
         let target_path = get_queue_path(playlist_name).await?;
         let mut file = fs::File::create(target_path).await?;
         let bytes = serde_json::to_vec_pretty(self)?;
-        file.write(&bytes).await?;
+        file.write_all(&bytes).await?;
+        //Write finishes in background otherwise, after lock of `update` is released
+        file.flush().await?;
         Ok(())
     }
 }
@@ -44,43 +123,52 @@ pub async fn handle_renaming_audio(
     new_name: String,
     prev_name: &str,
 ) -> Result<(), std::io::Error> {
-    let target_path = get_queue_path(playlist_name).await?;
-    let mut file = fs::File::open(&target_path).await?;
-    let mut buf = Vec::new();
-    file.read_to_end(&mut buf).await?;
-    let mut serialized: Queue = serde_json::from_slice(&buf)?;
-    let serialized_items = &mut serialized.items;
-    if let Some(pos) = serialized_items
-        .iter_mut()
-        .position(|x| x.name == prev_name)
-    {
-        serialized_items[pos].name = new_name;
-    }
-    let mut file = fs::File::create(target_path).await?;
-    file.write_all(&serde_json::to_vec_pretty(&serialized)?)
-        .await?;
-    Ok(())
+    Queue::update(playlist_name, |queue| {
+        if let Some(item) = queue.items.iter_mut().find(|x| x.name == prev_name) {
+            item.name = new_name;
+        }
+        Ok(())
+    })
+    .await
 }
 pub async fn handle_removing_audio(
     audio_name: &str,
     playlist_name: &str,
 ) -> Result<(), std::io::Error> {
-    let target_path = get_queue_path(playlist_name).await?;
-    let mut file = fs::File::open(&target_path).await?;
-    let mut buf = Vec::new();
-    file.read_to_end(&mut buf).await?;
-    let mut serialized: Queue = serde_json::from_slice(&buf)?;
-    let serialized_items = &mut serialized.items;
-    if let Some(pos) = serialized_items
-        .iter_mut()
-        .position(|x| x.name == audio_name)
+    Queue::update(playlist_name, |queue| {
+        if let Some(pos) = queue.items.iter().position(|x| x.name == audio_name) {
+            queue.items.remove(pos);
+        }
+        Ok(())
+    })
+    .await
+}
+pub async fn handle_trimming_audio(
+    playlist_name: &str,
+    audio_name: &str,
+    start: Option<Duration>,
+    end: Option<Duration>,
+) -> Result<(), std::io::Error> {
+    if let (Some(start), Some(end)) = (start, end)
+        && start >= end
     {
-        serialized_items.remove(pos);
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "trim start has to be before its end",
+        ));
     }
-    let mut file = fs::File::create(target_path).await?;
-    file.write_all(&serde_json::to_vec_pretty(&serialized)?)
-        .await?;
-    Ok(())
+    Queue::update(playlist_name, |queue| {
+        let Some(item) = queue.items.iter_mut().find(|x| x.name == audio_name) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("No audio named {audio_name} in playlist {playlist_name}"),
+            ));
+        };
+        item.start = start.map(|v| v.as_secs_f64());
+        item.end = end.map(|v| v.as_secs_f64());
+        Ok(())
+    })
+    .await
 }
 pub async fn handle_getting_queue(playlist_name: &str) -> Result<Vec<String>, std::io::Error> {
     let target_path = get_queue_path(playlist_name).await?;
@@ -92,8 +180,38 @@ pub async fn handle_getting_queue(playlist_name: &str) -> Result<Vec<String>, st
     Ok(audio_names)
 }
 async fn get_queue_path(playlist_name: &str) -> Result<PathBuf, std::io::Error> {
-    Ok(get_playlists_dir()
-        .await?
-        .join(playlist_name)
-        .join("queue.json"))
+    Ok(get_playlist_dir(playlist_name).await?.join("queue.json"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_offsets_count_as_missing() {
+        let mut item = QueueItem::new("a.mp3".into(), "a".into());
+        item.start = Some(1.5);
+        item.end = Some(f64::NAN);
+        assert_eq!(item.start_offset(), Some(Duration::from_millis(1500)));
+        assert_eq!(item.end_offset(), None);
+        item.start = Some(-1.0);
+        item.end = Some(f64::INFINITY);
+        assert_eq!(item.start_offset(), None);
+        assert_eq!(item.end_offset(), None);
+    }
+
+    #[tokio::test]
+    async fn concurrent_appends_keep_every_item() {
+        let playlist = "concurrent-appends";
+        crate::utils::create_playlist(playlist).await.unwrap();
+        let append = |name: &str| {
+            Queue::append_to_json(playlist, QueueItem::new(format!("{name}.mp3"), name.into()))
+        };
+        let (a, b) = tokio::join!(append("a"), append("b"));
+        a.unwrap();
+        b.unwrap();
+        let names = handle_getting_queue(playlist).await.unwrap();
+        assert_eq!(names.len(), 2);
+        assert!(names.contains(&"a".to_string()) && names.contains(&"b".to_string()));
+    }
 }
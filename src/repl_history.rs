@@ -0,0 +1,138 @@
+use std::collections::HashSet;
+
+use reedline::{
+    FileBackedHistory, History, HistoryItem, HistoryItemId, HistorySessionId, SearchQuery,
+};
+use tokio::fs;
+
+use crate::{config::Config, error::Error, utils::get_default_path};
+
+///REPL input history kept in `~/.yta-cli/history`, optionally without lines holding URLs
+pub struct ReplHistory {
+    inner: FileBackedHistory,
+    exclude_urls: bool,
+}
+impl ReplHistory {
+    ///Opens history file, dropping older copies of repeated lines
+    pub async fn open(config: &Config) -> Result<Self, Error> {
+        let path = get_default_path().await?.join("history");
+        if let Ok(content) = fs::read_to_string(&path).await
+            && let Some(deduped) = dedup(&content)
+        {
+            fs::write(&path, deduped).await?;
+        }
+        Ok(Self {
+            inner: FileBackedHistory::with_file(config.history_size, path)?,
+            exclude_urls: config.history_exclude_urls,
+        })
+    }
+}
+
+///Content keeping only last copy of each line, `None` when nothing repeats
+fn dedup(content: &str) -> Option<String> {
+    let mut seen = HashSet::new();
+    let mut lines: Vec<&str> = content
+        .lines()
+        .rev()
+        .filter(|line| seen.insert(*line))
+        .collect();
+    if lines.len() == content.lines().count() {
+        return None;
+    }
+    lines.reverse();
+    let mut deduped = lines.join("\n");
+    deduped.push('\n');
+    Some(deduped)
+}
+
+fn has_url(line: &str) -> bool {
+    line.split_whitespace().any(|word| word.contains("://"))
+}
+
+impl History for ReplHistory {
+    fn save(&mut self, h: HistoryItem) -> reedline::Result<HistoryItem> {
+        if self.exclude_urls && has_url(&h.command_line) {
+            //Same as what file history returns for lines it doesn't store
+            return Ok(HistoryItem { id: None, ..h });
+        }
+        self.inner.save(h)
+    }
+    fn load(&self, id: HistoryItemId) -> reedline::Result<HistoryItem> {
+        self.inner.load(id)
+    }
+    fn count(&self, query: SearchQuery) -> reedline::Result<i64> {
+        self.inner.count(query)
+    }
+    fn search(&self, query: SearchQuery) -> reedline::Result<Vec<HistoryItem>> {
+        self.inner.search(query)
+    }
+    fn update(
+        &mut self,
+        id: HistoryItemId,
+        updater: &dyn Fn(HistoryItem) -> HistoryItem,
+    ) -> reedline::Result<()> {
+        self.inner.update(id, updater)
+    }
+    fn clear(&mut self) -> reedline::Result<()> {
+        self.inner.clear()
+    }
+    fn delete(&mut self, h: HistoryItemId) -> reedline::Result<()> {
+        self.inner.delete(h)
+    }
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.inner.sync()
+    }
+    fn session(&self) -> Option<HistorySessionId> {
+        self.inner.session()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_last_copy_of_repeated_lines() {
+        assert_eq!(
+            dedup("play mix\nstatus\nplay mix\npause\nstatus\n").as_deref(),
+            Some("play mix\npause\nstatus\n")
+        );
+        assert_eq!(dedup("play mix\nstatus\n"), None);
+        assert_eq!(dedup(""), None);
+    }
+
+    #[test]
+    fn finds_urls_in_words() {
+        assert!(has_url("download mix https://youtu.be/abc"));
+        assert!(has_url("download mix -u http://example.com/a"));
+        assert!(!has_url("play mix --track 2"));
+        assert!(!has_url("search youtube.com"));
+    }
+
+    #[test]
+    fn skips_lines_with_urls_when_excluded() {
+        let mut history = ReplHistory {
+            inner: FileBackedHistory::new(10).unwrap(),
+            exclude_urls: true,
+        };
+        let saved = history
+            .save(HistoryItem::from_command_line(
+                "download mix https://youtu.be/abc",
+            ))
+            .unwrap();
+        assert!(saved.id.is_none());
+        let saved = history
+            .save(HistoryItem::from_command_line("play mix"))
+            .unwrap();
+        assert!(saved.id.is_some());
+        assert_eq!(
+            history
+                .count(SearchQuery::everything(
+                    reedline::SearchDirection::Forward,
+                    None
+                ))
+                .unwrap(),
+            1
+        );
+    }
+}
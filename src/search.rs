@@ -1,36 +1,517 @@
-use serde::Deserialize;
-use tokio::process::Command;
+use std::{
+    fmt::{self, Display},
+    time::Duration,
+};
 
-#[derive(Debug, Deserialize)]
+use crate::{
+    error::Error,
+    utils::{Paths, format_duration, run_yt_dlp},
+};
+use chrono::{Local, NaiveDate};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+const TITLE_WIDTH: usize = 50;
+const CHANNEL_WIDTH: usize = 24;
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchSource {
+    Youtube,
+    Ytmusic,
+    Soundcloud,
+}
+impl Display for SearchSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SearchSource::Youtube => write!(f, "YouTube"),
+            SearchSource::Ytmusic => write!(f, "YouTube Music"),
+            SearchSource::Soundcloud => write!(f, "SoundCloud"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchType {
+    Video,
+    Playlist,
+    Channel,
+}
+impl Display for SearchType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SearchType::Video => write!(f, "video"),
+            SearchType::Playlist => write!(f, "playlist"),
+            SearchType::Channel => write!(f, "channel"),
+        }
+    }
+}
+
+///What yt-dlp gets to search for up to `count` results
+fn search_target(
+    source: SearchSource,
+    kind: SearchType,
+    query: &str,
+    count: usize,
+) -> Result<String, Error> {
+    let query_param = encode_query(query);
+    match (source, kind) {
+        (SearchSource::Youtube, SearchType::Video) => Ok(format!("ytsearch{count}:{query}")),
+        (SearchSource::Youtube, _) => {
+            //`sp` is the same filter YouTube's own result page uses
+            let filter = if kind == SearchType::Playlist {
+                "EgIQAw%3D%3D"
+            } else {
+                "EgIQAg%3D%3D"
+            };
+            Ok(format!(
+                "https://www.youtube.com/results?search_query={query_param}&sp={filter}"
+            ))
+        }
+        (SearchSource::Ytmusic, _) => {
+            let section = match kind {
+                SearchType::Video => "songs",
+                SearchType::Playlist => "community%20playlists",
+                SearchType::Channel => "artists",
+            };
+            Ok(format!(
+                "https://music.youtube.com/search?q={query_param}#{section}"
+            ))
+        }
+        (SearchSource::Soundcloud, SearchType::Video) => Ok(format!("scsearch{count}:{query}")),
+        (SearchSource::Soundcloud, _) => Err(Error::UnsupportedSearch(source, kind)),
+    }
+}
+
+///Percent encodes text for use in URL query
+fn encode_query(text: &str) -> String {
+    let mut encoded = String::new();
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b' ' => encoded.push('+'),
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Video {
     pub title: String,
     pub id: String,
+    ///Length in seconds
+    #[serde(default)]
+    pub duration: Option<f64>,
+    #[serde(default)]
+    pub channel: Option<String>,
+    #[serde(default)]
+    pub view_count: Option<u64>,
+    ///Date in `YYYYMMDD` format as yt-dlp reports it
+    #[serde(default)]
+    pub upload_date: Option<String>,
+    #[serde(default, rename(deserialize = "webpage_url"))]
+    pub url: Option<String>,
+}
+impl Video {
+    ///What yt-dlp should be given to download this result
+    pub fn link(&self) -> String {
+        self.url.clone().unwrap_or_else(|| self.id.clone())
+    }
+    ///None also when yt-dlp reported negative or non-finite length
+    pub fn duration(&self) -> Option<Duration> {
+        self.duration
+            .and_then(|d| Duration::try_from_secs_f64(d).ok())
+    }
+    pub fn upload_date(&self) -> Option<NaiveDate> {
+        NaiveDate::parse_from_str(self.upload_date.as_deref()?, "%Y%m%d").ok()
+    }
+    ///Row of search results table, numbered the same way `DownloadResult` expects
+    pub fn row(&self, index: usize) -> String {
+        let duration = self.duration().map(format_duration).unwrap_or_default();
+        let views = self.view_count.map(format_views).unwrap_or_default();
+        let date = self
+            .upload_date()
+            .map(|d| d.to_string())
+            .unwrap_or_default();
+        format!(
+            "{index:>3}. {} {duration:>8}  {} {views:>6}  {date}",
+            fit(&self.title, TITLE_WIDTH),
+            fit(self.channel.as_deref().unwrap_or_default(), CHANNEL_WIDTH),
+        )
+    }
 }
-pub async fn search_youtube(query: &str, count: usize) -> Result<Vec<Video>, std::io::Error> {
-    let search_arg = format!("ytsearch{}:{}", count, query);
 
-    let output = Command::new("yt-dlp")
-        .args(&[
-            "--skip-download",
-            "-j", // JSON output
-            &search_arg,
-        ])
-        .output()
-        .await?;
+///Truncates or pads text to exactly `width` characters
+fn fit(text: &str, width: usize) -> String {
+    if text.chars().count() > width {
+        let truncated: String = text.chars().take(width - 1).collect();
+        format!("{truncated}…")
+    } else {
+        format!("{text:<width$}")
+    }
+}
 
-    if !output.status.success() {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "yt-dlp command failed",
-        ));
+fn format_views(views: u64) -> String {
+    match views {
+        0..1_000 => views.to_string(),
+        1_000..1_000_000 => format!("{:.1}K", views as f64 / 1e3),
+        1_000_000..1_000_000_000 => format!("{:.1}M", views as f64 / 1e6),
+        _ => format!("{:.1}B", views as f64 / 1e9),
     }
+}
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
+pub struct SearchResults {
+    pub videos: Vec<Video>,
+    ///Lines of yt-dlp output which couldn't be parsed
+    pub errors: Vec<serde_json::Error>,
+}
+
+///Drops results nobody wants, values yt-dlp didn't report don't exclude anything
+///except for `channel` and `after`
+#[derive(Default)]
+pub struct SearchFilter {
+    pub min_duration: Option<Duration>,
+    pub max_duration: Option<Duration>,
+    pub after: Option<NaiveDate>,
+    pub channel: Option<String>,
+    ///Words that must not be in title, lowercase
+    pub exclude: Vec<String>,
+}
+impl SearchFilter {
+    pub fn matches(&self, video: &Video) -> bool {
+        if let Some(duration) = video.duration()
+            && (self.min_duration.is_some_and(|min| duration < min)
+                || self.max_duration.is_some_and(|max| duration > max))
+        {
+            return false;
+        }
+        if let Some(after) = self.after
+            && video.upload_date().is_none_or(|date| date < after)
+        {
+            return false;
+        }
+        if let Some(channel) = &self.channel
+            && !video
+                .channel
+                .as_deref()
+                .is_some_and(|c| c.to_lowercase().contains(channel))
+        {
+            return false;
+        }
+        let title = video.title.to_lowercase();
+        !self.exclude.iter().any(|word| title.contains(word))
+    }
+}
 
-    let results: Vec<Video> = stdout
-        .lines()
-        .filter_map(|line| serde_json::from_str::<Video>(line).ok())
-        .collect();
+///Last search with every page fetched so far, so results from earlier pages stay downloadable
+pub struct SearchSession {
+    pub query: String,
+    pub source: SearchSource,
+    pub kind: SearchType,
+    pub page_size: usize,
+    pub flat: bool,
+    pub json: bool,
+    pub filter: SearchFilter,
+    ///Unix time in milliseconds when search was made
+    pub started: i64,
+    ///Page shown last, counted from 0
+    pub page: usize,
+    ///Filtered results of pages fetched so far, in order
+    pages: Vec<Vec<Video>>,
+}
+impl SearchSession {
+    pub fn new(
+        query: String,
+        source: SearchSource,
+        kind: SearchType,
+        page_size: usize,
+        flat: bool,
+        json: bool,
+        filter: SearchFilter,
+    ) -> Self {
+        Self {
+            query,
+            source,
+            kind,
+            page_size,
+            //Playlists and channels would get expanded into every video they contain
+            flat: flat || kind != SearchType::Video,
+            json,
+            filter,
+            started: Local::now().timestamp_millis(),
+            page: 0,
+            pages: Vec::new(),
+        }
+    }
+    pub fn cached_page(&self, page: usize) -> Option<&Vec<Video>> {
+        self.pages.get(page)
+    }
+    ///Adds page after the last fetched one, dropping results filter doesn't match
+    pub fn push_page(&mut self, mut videos: Vec<Video>) {
+        videos.retain(|video| self.filter.matches(video));
+        self.pages.push(videos);
+    }
+    ///Number of first result on page, as shown to user
+    pub fn first_index(&self, page: usize) -> usize {
+        self.pages.iter().take(page).map(Vec::len).sum::<usize>() + 1
+    }
+    ///How many results all fetched pages hold
+    pub fn result_count(&self) -> usize {
+        self.pages.iter().map(Vec::len).sum()
+    }
+    pub fn results(&self) -> impl Iterator<Item = &Video> {
+        self.pages.iter().flatten()
+    }
+    ///Result by number shown to user, counted from 1 across all pages
+    pub fn get(&self, index: usize) -> Option<&Video> {
+        self.results().nth(index.checked_sub(1)?)
+    }
+}
+
+///Fetches one page of results, `flat` skips extracting every video which is much faster
+///but leaves out upload dates
+pub async fn search_youtube(
+    paths: &Paths,
+    session: &SearchSession,
+    page: usize,
+) -> Result<SearchResults, Error> {
+    let first = page * session.page_size + 1;
+    let last = (page + 1) * session.page_size;
+    let flat = session.flat;
+    let search_arg = search_target(session.source, session.kind, &session.query, last)?;
+    let items = format!("{first}-{last}");
+
+    let mut args = vec![
+        "--skip-download",
+        "-j", // JSON output
+        "--playlist-items",
+        &items,
+    ];
+    if flat {
+        args.push("--flat-playlist");
+    }
+    args.extend(["--", &search_arg]);
+    let stdout = run_yt_dlp(paths, args).await?;
+
+    let mut results = SearchResults {
+        videos: Vec::new(),
+        errors: Vec::new(),
+    };
+    for line in stdout.lines().filter(|line| !line.trim().is_empty()) {
+        match parse_video(line, flat) {
+            Ok(video) => results.videos.push(video),
+            Err(e) => results.errors.push(e),
+        }
+    }
 
     Ok(results)
 }
+
+///Flat entries have no `webpage_url`, their `url` points to the video page instead of media
+fn parse_video(line: &str, flat: bool) -> Result<Video, serde_json::Error> {
+    if !flat {
+        return serde_json::from_str(line);
+    }
+    let mut value: serde_json::Value = serde_json::from_str(line)?;
+    if value.get("webpage_url").is_none()
+        && let Some(url) = value.get("url").cloned()
+    {
+        value["webpage_url"] = url;
+    }
+    serde_json::from_value(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn video(title: &str) -> Video {
+        serde_json::from_value(serde_json::json!({"title": title, "id": "id"})).unwrap()
+    }
+
+    #[test]
+    fn fits_text_to_width() {
+        assert_eq!(fit("abc", 5), "abc  ");
+        assert_eq!(fit("abcde", 5), "abcde");
+        assert_eq!(fit("abcdef", 5), "abcd…");
+        assert_eq!(fit("żółwie", 4), "żół…");
+    }
+
+    #[test]
+    fn formats_views_shortly() {
+        assert_eq!(format_views(999), "999");
+        assert_eq!(format_views(1_234), "1.2K");
+        assert_eq!(format_views(12_345_678), "12.3M");
+        assert_eq!(format_views(2_000_000_000), "2.0B");
+    }
+
+    #[test]
+    fn row_shows_known_values() {
+        let mut v = video("Song");
+        v.duration = Some(3725.0);
+        v.channel = Some("Band".into());
+        v.view_count = Some(1_500);
+        v.upload_date = Some("20240131".into());
+        let row = v.row(7);
+        assert!(row.starts_with("  7. Song "));
+        assert!(row.contains("1:02:05"));
+        assert!(row.contains("Band"));
+        assert!(row.contains("1.5K"));
+        assert!(row.ends_with("2024-01-31"));
+    }
+
+    #[test]
+    fn invalid_durations_count_as_missing() {
+        for duration in [-1.0, f64::NAN, f64::INFINITY, 1e30] {
+            let mut v = video("Song");
+            v.duration = Some(duration);
+            assert_eq!(v.duration(), None);
+            assert!(!v.row(1).contains(':'));
+        }
+    }
+
+    #[test]
+    fn flat_results_link_to_their_page() {
+        let line =
+            r#"{"title": "Song", "id": "abc", "url": "https://www.youtube.com/watch?v=abc"}"#;
+        let flat = parse_video(line, true).unwrap();
+        assert_eq!(
+            flat.url.as_deref(),
+            Some("https://www.youtube.com/watch?v=abc")
+        );
+        let full = parse_video(line, false).unwrap();
+        assert_eq!(full.url, None);
+
+        let line = r#"{"title": "Song", "id": "abc", "url": "https://media", "webpage_url": "https://page"}"#;
+        let flat = parse_video(line, true).unwrap();
+        assert_eq!(flat.url.as_deref(), Some("https://page"));
+        assert!(parse_video("{not json", true).is_err());
+    }
+
+    #[test]
+    fn encodes_query_for_urls() {
+        assert_eq!(encode_query("daft punk"), "daft+punk");
+        assert_eq!(encode_query("a&b=c/ż"), "a%26b%3Dc%2F%C5%BC");
+        assert_eq!(encode_query("A-z_0.9~"), "A-z_0.9~");
+    }
+
+    #[test]
+    fn builds_search_targets() {
+        let target = |source, kind| search_target(source, kind, "daft punk", 10).ok();
+        assert_eq!(
+            target(SearchSource::Youtube, SearchType::Video).as_deref(),
+            Some("ytsearch10:daft punk")
+        );
+        assert_eq!(
+            target(SearchSource::Youtube, SearchType::Playlist).as_deref(),
+            Some("https://www.youtube.com/results?search_query=daft+punk&sp=EgIQAw%3D%3D")
+        );
+        assert_eq!(
+            target(SearchSource::Ytmusic, SearchType::Channel).as_deref(),
+            Some("https://music.youtube.com/search?q=daft+punk#artists")
+        );
+        assert_eq!(
+            target(SearchSource::Soundcloud, SearchType::Video).as_deref(),
+            Some("scsearch10:daft punk")
+        );
+        assert!(target(SearchSource::Soundcloud, SearchType::Playlist).is_none());
+    }
+
+    #[test]
+    fn links_results_by_page_or_id() {
+        let mut v = video("Song");
+        assert_eq!(v.link(), "id");
+        v.url = Some("https://page".into());
+        assert_eq!(v.link(), "https://page");
+    }
+
+    #[test]
+    fn filter_drops_unwanted_results() {
+        let mut v = video("Live Song (Remix)");
+        v.duration = Some(200.0);
+        v.channel = Some("The Band".into());
+        v.upload_date = Some("20240102".into());
+        let date = |s| NaiveDate::parse_from_str(s, "%Y-%m-%d").ok();
+        let matches = |filter: SearchFilter| filter.matches(&v);
+
+        assert!(matches(SearchFilter::default()));
+        assert!(matches(SearchFilter {
+            min_duration: Some(Duration::from_secs(100)),
+            max_duration: Some(Duration::from_secs(300)),
+            ..Default::default()
+        }));
+        assert!(!matches(SearchFilter {
+            max_duration: Some(Duration::from_secs(100)),
+            ..Default::default()
+        }));
+        assert!(matches(SearchFilter {
+            after: date("2024-01-02"),
+            ..Default::default()
+        }));
+        assert!(!matches(SearchFilter {
+            after: date("2024-01-03"),
+            ..Default::default()
+        }));
+        assert!(matches(SearchFilter {
+            channel: Some("band".into()),
+            ..Default::default()
+        }));
+        assert!(!matches(SearchFilter {
+            channel: Some("other".into()),
+            ..Default::default()
+        }));
+        assert!(!matches(SearchFilter {
+            exclude: vec!["remix".into()],
+            ..Default::default()
+        }));
+    }
+
+    #[test]
+    fn filter_keeps_unknown_values_except_date_and_channel() {
+        let mut v = video("Song");
+        v.duration = Some(f64::NAN);
+        let durations = SearchFilter {
+            min_duration: Some(Duration::from_secs(60)),
+            max_duration: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+        assert!(durations.matches(&v));
+        let after = SearchFilter {
+            after: NaiveDate::from_ymd_opt(2024, 1, 1),
+            ..Default::default()
+        };
+        assert!(!after.matches(&v));
+        let channel = SearchFilter {
+            channel: Some("band".into()),
+            ..Default::default()
+        };
+        assert!(!channel.matches(&v));
+    }
+
+    #[test]
+    fn numbers_results_across_pages() {
+        let mut session = SearchSession::new(
+            "song".into(),
+            SearchSource::Youtube,
+            SearchType::Video,
+            3,
+            false,
+            false,
+            SearchFilter::default(),
+        );
+        session.push_page(vec![video("a"), video("b"), video("c")]);
+        session.push_page(vec![video("d")]);
+        assert_eq!(session.first_index(0), 1);
+        assert_eq!(session.first_index(1), 4);
+        assert_eq!(session.first_index(2), 5);
+        assert_eq!(session.get(4).map(|v| v.title.as_str()), Some("d"));
+        assert!(session.get(0).is_none());
+        assert!(session.get(5).is_none());
+        assert_eq!(session.cached_page(1).map(Vec::len), Some(1));
+        assert!(session.cached_page(2).is_none());
+    }
+}
@@ -0,0 +1,542 @@
+use std::time::Duration;
+
+use ratatui::{
+    DefaultTerminal, Frame,
+    crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind},
+    layout::{Constraint, Layout},
+    style::{Color, Modifier, Style},
+    text::Line,
+    widgets::{Block, Gauge, List, ListState, Paragraph},
+};
+
+use tokio::{
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    task::JoinHandle,
+};
+
+use crate::{
+    App, Commands, Outcome,
+    config::Config,
+    console,
+    playlist::{PlaybackStatus, PlaylistControl},
+    queue::{Queue, handle_removing_audio, handle_renaming_audio},
+    run_command,
+    utils::{format_duration, get_playlists},
+};
+
+///How long to wait for key before redrawing playback progress
+const TICK: Duration = Duration::from_millis(200);
+const VOLUME_STEP: f32 = 0.05;
+const HELP: &str = "Enter:play e:enqueue r:rename x:remove d:download Space:pause n/p:skip [/]:seek +/-:volume q:quit";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Pane {
+    Playlists,
+    Queue,
+}
+
+///What line typed at the bottom is for
+enum Input {
+    ///Holds current name of track
+    Rename(String),
+    Download,
+}
+
+struct Tui {
+    playlists: Vec<String>,
+    playlists_state: ListState,
+    queue: Queue,
+    queue_state: ListState,
+    focus: Pane,
+    input: Option<(Input, String)>,
+    status: Option<PlaybackStatus>,
+    message: String,
+    seek_step: i64,
+    ///Lines commands run from TUI print, shown as message instead
+    output: (UnboundedSender<String>, UnboundedReceiver<String>),
+    ///Whether playback going on was started here, only that one stops on exit
+    started_playback: bool,
+}
+
+///Runs full screen mode until `q`, playback started from it stops on exit
+pub async fn run(app: &mut App) -> Outcome {
+    let mut tui = match Tui::load().await {
+        Ok(v) => v,
+        Err(e) => {
+            say!("Failed to load playlists: {e}");
+            return Outcome::Failed;
+        }
+    };
+    let mut terminal = match ratatui::try_init() {
+        Ok(v) => v,
+        Err(e) => {
+            say!("Failed to start TUI: {e}");
+            return Outcome::Failed;
+        }
+    };
+    let (events, reader) = read_events();
+    let result = tui.run(&mut terminal, events, app).await;
+    //Reader stops within a tick once events are dropped, and mustn't take keys of REPL
+    let _ = reader.await;
+    ratatui::restore();
+    if tui.started_playback {
+        app.stop_playback();
+    }
+    match result {
+        Ok(()) => Outcome::Done,
+        Err(e) => {
+            say!("TUI error: {e}");
+            Outcome::Failed
+        }
+    }
+}
+
+///Reads terminal events on blocking thread, which ends once returned receiver is dropped
+fn read_events() -> (UnboundedReceiver<std::io::Result<Event>>, JoinHandle<()>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let reader = tokio::task::spawn_blocking(move || {
+        while !tx.is_closed() {
+            let event = match event::poll(TICK) {
+                Ok(false) => continue,
+                Ok(true) => event::read(),
+                Err(e) => Err(e),
+            };
+            let failed = event.is_err();
+            if tx.send(event).is_err() || failed {
+                return;
+            }
+        }
+    });
+    (rx, reader)
+}
+
+impl Tui {
+    async fn load() -> Result<Self, std::io::Error> {
+        let mut playlists = get_playlists().await?;
+        playlists.sort();
+        let config = Config::load().await.unwrap_or_default();
+        let mut tui = Self {
+            playlists_state: ListState::default()
+                .with_selected((!playlists.is_empty()).then_some(0)),
+            playlists,
+            queue: Queue::default(),
+            queue_state: ListState::default(),
+            focus: Pane::Playlists,
+            input: None,
+            status: None,
+            message: String::new(),
+            seek_step: config.seek_step_secs as i64,
+            output: mpsc::unbounded_channel(),
+            started_playback: false,
+        };
+        tui.load_queue().await;
+        Ok(tui)
+    }
+
+    fn playlist(&self) -> Option<&String> {
+        self.playlists.get(self.playlists_state.selected()?)
+    }
+
+    fn track(&self) -> Option<(usize, &str)> {
+        let index = self.queue_state.selected()?;
+        Some((index, &self.queue.items.get(index)?.name))
+    }
+
+    ///Reads queue of selected playlist again, keeping selection in range
+    async fn load_queue(&mut self) {
+        self.queue = match self.playlist() {
+            Some(name) => match Queue::from_queue_json(name).await {
+                Ok(q) => q,
+                Err(e) => {
+                    self.message = format!("Failed to load queue: {e}");
+                    Queue::default()
+                }
+            },
+            None => Queue::default(),
+        };
+        let len = self.queue.items.len();
+        let selected = self
+            .queue_state
+            .selected()
+            .map(|i| i.min(len.saturating_sub(1)));
+        self.queue_state
+            .select(if len == 0 { None } else { selected.or(Some(0)) });
+    }
+
+    async fn run(
+        &mut self,
+        terminal: &mut DefaultTerminal,
+        mut events: UnboundedReceiver<std::io::Result<Event>>,
+        app: &mut App,
+    ) -> std::io::Result<()> {
+        loop {
+            self.status = app.playback_status().await;
+            //Messages of commands, downloads and playback, finished downloads change queue
+            let mut printed = false;
+            while let Some(line) = app.printer.get_line() {
+                self.message = line;
+                printed = true;
+            }
+            while let Ok(line) = self.output.1.try_recv() {
+                self.message = line;
+                printed = true;
+            }
+            if printed {
+                self.load_queue().await;
+            }
+            let downloads = app.downloads.active();
+            terminal.draw(|frame| self.draw(frame, downloads))?;
+
+            let event = tokio::select! {
+                event = events.recv() => event,
+                _ = tokio::time::sleep(TICK) => continue,
+            };
+            let Some(Event::Key(key)) = event.transpose()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            if self.input.is_some() {
+                self.handle_input_key(key, app).await;
+            } else if !self.handle_key(key, app).await {
+                return Ok(());
+            }
+        }
+    }
+
+    ///Returns false once TUI should close
+    async fn handle_key(&mut self, key: KeyEvent, app: &mut App) -> bool {
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Tab => {
+                self.focus = match self.focus {
+                    Pane::Playlists => Pane::Queue,
+                    Pane::Queue => Pane::Playlists,
+                }
+            }
+            KeyCode::Left => self.focus = Pane::Playlists,
+            KeyCode::Right => self.focus = Pane::Queue,
+            KeyCode::Up | KeyCode::Char('k') => self.move_selection(false).await,
+            KeyCode::Down | KeyCode::Char('j') => self.move_selection(true).await,
+            KeyCode::Enter => {
+                let Some(name) = self.playlist().cloned() else {
+                    return true;
+                };
+                let track = match self.focus {
+                    Pane::Playlists => 1,
+                    Pane::Queue => self.track().map_or(1, |(index, _)| index + 1),
+                };
+                let play = Commands::Play {
+                    name: Some(name),
+                    track: Some(track),
+                    hit: None,
+                };
+                self.started_playback |= self.run_command(play, app).await != Outcome::Failed;
+            }
+            KeyCode::Char('e') => {
+                if let Some((index, name)) = self.track() {
+                    let message = format!("Enqueued {name}");
+                    let item = self.queue.items[index].clone();
+                    if self.control(app, PlaylistControl::Enqueue(item)) {
+                        self.message = message;
+                    }
+                }
+            }
+            KeyCode::Char('r') => {
+                if let Some((_, name)) = self.track() {
+                    self.input = Some((Input::Rename(name.to_string()), name.to_string()));
+                }
+            }
+            KeyCode::Char('x') | KeyCode::Delete => {
+                if let (Some(playlist), Some((_, name))) = (self.playlist(), self.track()) {
+                    self.message = match handle_removing_audio(name, playlist).await {
+                        Ok(()) => format!("Removed {name}"),
+                        Err(e) => format!("Failed to remove audio: {e}"),
+                    };
+                    self.load_queue().await;
+                }
+            }
+            KeyCode::Char('d') if self.playlist().is_some() => {
+                self.input = Some((Input::Download, String::new()));
+            }
+            KeyCode::Char(' ') => {
+                self.control(app, PlaylistControl::TogglePause);
+            }
+            KeyCode::Char('n') => {
+                self.control(app, PlaylistControl::Skip);
+            }
+            KeyCode::Char('p') => {
+                self.control(app, PlaylistControl::Previous);
+            }
+            KeyCode::Char(']') => {
+                self.control(app, PlaylistControl::SkipBy(self.seek_step));
+            }
+            KeyCode::Char('[') => {
+                self.control(app, PlaylistControl::SkipBy(-self.seek_step));
+            }
+            KeyCode::Char('+') | KeyCode::Char('=') => self.change_volume(app, VOLUME_STEP),
+            KeyCode::Char('-') => self.change_volume(app, -VOLUME_STEP),
+            _ => {}
+        }
+        true
+    }
+
+    async fn handle_input_key(&mut self, key: KeyEvent, app: &mut App) {
+        let Some((_, text)) = &mut self.input else {
+            return;
+        };
+        match key.code {
+            KeyCode::Esc => self.input = None,
+            KeyCode::Backspace => {
+                text.pop();
+            }
+            KeyCode::Char(c) => text.push(c),
+            KeyCode::Enter => {
+                let Some((input, text)) = self.input.take() else {
+                    return;
+                };
+                let Some(playlist) = self.playlist().cloned() else {
+                    return;
+                };
+                match input {
+                    Input::Rename(current) => {
+                        self.message =
+                            match handle_renaming_audio(&playlist, text.clone(), &current).await {
+                                Ok(()) => format!("Renamed {current} to {text}"),
+                                Err(e) => format!("Failed to rename audio: {e}"),
+                            };
+                        self.load_queue().await;
+                    }
+                    Input::Download => {
+                        let urls: Vec<String> = text.split_whitespace().map(String::from).collect();
+                        if !urls.is_empty() {
+                            let download = Commands::Download {
+                                urls,
+                                playlist_name: playlist,
+                            };
+                            self.run_command(download, app).await;
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    ///Runs command with its output shown as message, printing would break the screen
+    async fn run_command(&mut self, command: Commands, app: &mut App) -> Outcome {
+        //Boxed as `tui` itself runs from `run_command`
+        console::capture(self.output.0.clone(), Box::pin(run_command(command, app))).await
+    }
+
+    async fn move_selection(&mut self, down: bool) {
+        let (state, len) = match self.focus {
+            Pane::Playlists => (&mut self.playlists_state, self.playlists.len()),
+            Pane::Queue => (&mut self.queue_state, self.queue.items.len()),
+        };
+        if len == 0 {
+            return;
+        }
+        let current = state.selected().unwrap_or(0);
+        state.select(Some(if down {
+            (current + 1).min(len - 1)
+        } else {
+            current.saturating_sub(1)
+        }));
+        if self.focus == Pane::Playlists {
+            self.queue_state.select(None);
+            self.load_queue().await;
+        }
+    }
+
+    ///Sends control to playback, returns whether there was any to send it to
+    fn control(&mut self, app: &App, control: PlaylistControl) -> bool {
+        let sent = app.control(control);
+        if !sent {
+            self.message = "Currently no playlist is playing".to_string();
+        }
+        sent
+    }
+
+    fn change_volume(&mut self, app: &App, step: f32) {
+        if let Some(volume) = self
+            .status
+            .as_ref()
+            .map(|s| (s.volume + step).clamp(0.0, 1.0))
+        {
+            self.control(app, PlaylistControl::Volume(volume));
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame, downloads: usize) {
+        let [main, now_playing, footer] = Layout::vertical([
+            Constraint::Min(3),
+            Constraint::Length(4),
+            Constraint::Length(2),
+        ])
+        .areas(frame.area());
+        let [left, middle] =
+            Layout::horizontal([Constraint::Percentage(30), Constraint::Percentage(70)])
+                .areas(main);
+
+        let pane_block = |title: String, pane: Pane| {
+            let block = Block::bordered().title(title);
+            if self.focus == pane {
+                block.border_style(Style::new().fg(Color::Yellow))
+            } else {
+                block
+            }
+        };
+        let highlight = Style::new().add_modifier(Modifier::REVERSED);
+
+        let playlists = List::new(self.playlists.iter().map(String::as_str))
+            .block(pane_block("Playlists".to_string(), Pane::Playlists))
+            .highlight_style(highlight);
+        frame.render_stateful_widget(playlists, left, &mut self.playlists_state);
+
+        let title = self
+            .playlist()
+            .map_or("Queue".to_string(), |name| format!("Queue: {name}"));
+        let playing = self
+            .status
+            .as_ref()
+            .filter(|s| Some(&s.playlist) == self.playlist())
+            .map(|s| s.index);
+        let tracks = self.queue.items.iter().enumerate().map(|(i, item)| {
+            let marker = if playing == Some(i) { "▶" } else { " " };
+            format!("{marker}{:>3}. {}", i + 1, item.name)
+        });
+        let queue = List::new(tracks)
+            .block(pane_block(title, Pane::Queue))
+            .highlight_style(highlight);
+        frame.render_stateful_widget(queue, middle, &mut self.queue_state);
+
+        let mut block = Block::bordered().title("Now playing");
+        if let Some(status) = &self.status {
+            block = block.title_bottom(format!("Volume {:.0}%", status.volume * 100.0));
+        }
+        if downloads > 0 {
+            block =
+                block.title_bottom(Line::from(format!("Downloads {downloads}")).right_aligned());
+        }
+        let inner = block.inner(now_playing);
+        frame.render_widget(block, now_playing);
+        let [track, progress] =
+            Layout::vertical([Constraint::Length(1), Constraint::Length(1)]).areas(inner);
+        match &self.status {
+            Some(status) => {
+                let state = if status.paused { "⏸" } else { "▶" };
+                frame.render_widget(
+                    Paragraph::new(format!(
+                        "{state} {}. {}  ({})",
+                        status.index + 1,
+                        status.track,
+                        status.playlist
+                    )),
+                    track,
+                );
+                let position = format_duration(status.position);
+                let (ratio, label) = match status.duration {
+                    Some(d) if !d.is_zero() => (
+                        (status.position.as_secs_f64() / d.as_secs_f64()).clamp(0.0, 1.0),
+                        format!("{position} / {}", format_duration(d)),
+                    ),
+                    _ => (0.0, position),
+                };
+                frame.render_widget(Gauge::default().ratio(ratio).label(label), progress);
+            }
+            None => frame.render_widget(Paragraph::new("Nothing is playing"), track),
+        }
+
+        let first_line = match &self.input {
+            Some((Input::Rename(_), text)) => format!("New name: {text}_"),
+            Some((Input::Download, text)) => format!("URLs to download: {text}_"),
+            None => self.message.clone(),
+        };
+        frame.render_widget(
+            Paragraph::new(vec![Line::from(first_line), Line::from(HELP)]),
+            footer,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::QueueItem;
+
+    async fn playlist(name: &str, tracks: usize) {
+        crate::utils::create_playlist(name).await.unwrap();
+        for i in 0..tracks {
+            let item = QueueItem::new(format!("{i}.mp3"), format!("track {i}"));
+            Queue::append_to_json(name, item).await.unwrap();
+        }
+    }
+
+    async fn tui(playlists: &[&str]) -> Tui {
+        let mut tui = Tui {
+            playlists: playlists.iter().map(|name| name.to_string()).collect(),
+            playlists_state: ListState::default().with_selected(Some(0)),
+            queue: Queue::default(),
+            queue_state: ListState::default(),
+            focus: Pane::Playlists,
+            input: None,
+            status: None,
+            message: String::new(),
+            seek_step: 10,
+            output: mpsc::unbounded_channel(),
+            started_playback: false,
+        };
+        tui.load_queue().await;
+        tui
+    }
+
+    #[tokio::test]
+    async fn selection_stays_within_lists() {
+        playlist("tui-three", 3).await;
+        playlist("tui-empty", 0).await;
+        let mut tui = tui(&["tui-three", "tui-empty"]).await;
+        assert_eq!(tui.queue_state.selected(), Some(0));
+
+        tui.focus = Pane::Queue;
+        for _ in 0..5 {
+            tui.move_selection(true).await;
+        }
+        assert_eq!(tui.track().map(|(i, _)| i), Some(2));
+        for _ in 0..5 {
+            tui.move_selection(false).await;
+        }
+        assert_eq!(tui.track(), Some((0, "track 0")));
+
+        tui.focus = Pane::Playlists;
+        tui.move_selection(true).await;
+        tui.move_selection(true).await;
+        assert_eq!(tui.playlist().map(String::as_str), Some("tui-empty"));
+        assert_eq!(tui.queue_state.selected(), None);
+        tui.focus = Pane::Queue;
+        tui.move_selection(true).await;
+        assert_eq!(tui.track(), None);
+    }
+
+    #[tokio::test]
+    async fn reloaded_queue_keeps_selection_in_range() {
+        playlist("tui-shrinking", 3).await;
+        let mut tui = tui(&["tui-shrinking"]).await;
+        tui.queue_state.select(Some(2));
+        handle_removing_audio("track 2", "tui-shrinking")
+            .await
+            .unwrap();
+        tui.load_queue().await;
+        assert_eq!(tui.track(), Some((1, "track 1")));
+
+        handle_removing_audio("track 0", "tui-shrinking")
+            .await
+            .unwrap();
+        handle_removing_audio("track 1", "tui-shrinking")
+            .await
+            .unwrap();
+        tui.load_queue().await;
+        assert_eq!(tui.queue_state.selected(), None);
+    }
+}
@@ -1,4 +1,8 @@
-use std::path::{Path, PathBuf};
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use tokio::{fs, process::Command};
 use which::which;
@@ -16,40 +20,91 @@ pub async fn get_playlists() -> Result<Vec<String>, std::io::Error> {
     }
 }
 pub async fn get_default_path() -> Result<PathBuf, std::io::Error> {
-    let dir = dirs_next::home_dir()
+    let dir = default_dir()?;
+    fs::create_dir_all(&dir).await?;
+    Ok(dir)
+}
+fn default_dir() -> Result<PathBuf, std::io::Error> {
+    //Tests get their own directory instead of real playlists
+    if cfg!(test) {
+        return Ok(std::env::temp_dir().join(format!("yta-cli-test-{}", std::process::id())));
+    }
+    Ok(dirs_next::home_dir()
         .ok_or(std::io::Error::new(
             std::io::ErrorKind::NotFound,
             "Couldnt find home dir",
         ))?
-        .join(".yta-cli");
-    fs::create_dir_all(&dir).await?;
-    Ok(dir)
+        .join(".yta-cli"))
 }
 
 pub async fn create_playlist(playlist_name: &str) -> Result<(), std::io::Error> {
-    let playlist_dir = get_playlists_dir().await?.join(playlist_name);
+    let playlist_dir = get_playlist_dir(playlist_name).await?;
     fs::create_dir_all(playlist_dir).await?;
     Ok(())
 }
 pub async fn get_playlists_dir() -> Result<PathBuf, std::io::Error> {
-    Ok(get_default_path().await?.join("playlists"))
+    get_default_path().await?;
+    playlists_dir()
+}
+///Directory of one playlist, every path built from playlist name goes through here
+pub async fn get_playlist_dir(playlist_name: &str) -> Result<PathBuf, std::io::Error> {
+    get_default_path().await?;
+    playlist_dir(playlist_name)
+}
+///`get_playlists_dir` for code that can't await, like completion. Creates nothing
+pub fn playlists_dir() -> Result<PathBuf, std::io::Error> {
+    Ok(default_dir()?.join("playlists"))
+}
+///`get_playlist_dir` for code that can't await, like completion. Creates nothing
+pub fn playlist_dir(playlist_name: &str) -> Result<PathBuf, std::io::Error> {
+    check_playlist_name(playlist_name)?;
+    Ok(playlists_dir()?.join(playlist_name))
+}
+///Rejects names that would point outside of their own directory in playlists directory
+pub fn check_playlist_name(playlist_name: &str) -> Result<(), std::io::Error> {
+    let invalid = playlist_name.is_empty()
+        || playlist_name == "."
+        || playlist_name.contains("..")
+        || playlist_name.contains(['/', '\\']);
+    if invalid {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid playlist name: {playlist_name}"),
+        ));
+    }
+    Ok(())
 }
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct Paths {
     pub yt_dlp_path: PathBuf,
     pub ffmpeg_path: Option<PathBuf>,
 }
-pub async fn get_title_of_url(ytdlp_path: PathBuf, url: &str) -> Result<String, Error> {
-    let output = Command::new(ytdlp_path)
-        .arg("-e")
-        .arg(url)
-        .output()
-        .await?
-        .stdout;
-    let str_output = String::from_utf8(output)?;
+///Runs yt-dlp found at startup and returns its stdout, every yt-dlp call goes through here
+pub async fn run_yt_dlp<I, S>(paths: &Paths, args: I) -> Result<String, Error>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let mut cmd = Command::new(&paths.yt_dlp_path);
+    if let Some(path) = &paths.ffmpeg_path {
+        cmd.arg("--ffmpeg-location").arg(path);
+    }
+    let output = cmd.args(args).output().await?;
 
-    Ok(str_output)
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let message = stderr
+            .lines()
+            .rfind(|line| !line.trim().is_empty())
+            .map(|line| line.trim().to_string())
+            .unwrap_or_else(|| format!("exited with {}", output.status));
+        return Err(Error::YtDlp(message));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+pub async fn get_title_of_url(paths: &Paths, url: &str) -> Result<String, Error> {
+    run_yt_dlp(paths, ["-e", "--", url]).await
 }
 async fn list_subdir_names_async<P: AsRef<Path>>(dir: P) -> Result<Vec<String>, std::io::Error> {
     let mut names = Vec::new();
@@ -77,7 +132,148 @@ pub async fn get_programs_paths() -> Paths {
         }
     };
     Paths {
-        yt_dlp_path: yt_dlp_path,
-        ffmpeg_path: ffmpeg_path,
+        yt_dlp_path,
+        ffmpeg_path,
+    }
+}
+
+///Parses durations like `90`, `45s`, `30m`, `1h30m`, `1:23` or `1:02:03`
+pub fn parse_duration(input: &str) -> Result<Duration, Error> {
+    let invalid = || Error::InvalidDuration(input.to_string());
+    let trimmed = input.trim();
+    if trimmed.is_empty() {
+        return Err(invalid());
+    }
+    let secs = if trimmed.contains(':') {
+        let mut secs = 0f64;
+        for part in trimmed.split(':') {
+            let value = part.parse::<f64>().ok().filter(|v| *v >= 0.0);
+            secs = secs * 60.0 + value.ok_or_else(invalid)?;
+        }
+        secs
+    } else if let Ok(secs) = trimmed.parse::<f64>() {
+        secs
+    } else {
+        let mut total = 0f64;
+        let mut number = String::new();
+        for c in trimmed.chars() {
+            match c {
+                '0'..='9' | '.' => number.push(c),
+                'h' | 'm' | 's' => {
+                    let value: f64 = number.parse().map_err(|_| invalid())?;
+                    number.clear();
+                    total += match c {
+                        'h' => value * 3600.0,
+                        'm' => value * 60.0,
+                        _ => value,
+                    };
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        if !number.is_empty() {
+            return Err(invalid());
+        }
+        total
+    };
+    // also rules out NaN and infinity, which `parse::<f64>` accepts
+    if !secs.is_finite() || secs < 0.0 {
+        return Err(invalid());
+    }
+    Duration::try_from_secs_f64(secs).map_err(|_| invalid())
+}
+
+///Parses result selections like `2`, `1-3,5` or `all` into 1-based indexes up to `total`
+pub fn parse_selection(input: &str, total: usize) -> Option<Vec<usize>> {
+    let input = input.trim();
+    if input == "all" {
+        return Some((1..=total).collect());
+    }
+    let mut indexes = Vec::new();
+    for part in input.split(',') {
+        let (start, end) = match part.split_once('-') {
+            Some((start, end)) => (start.trim().parse().ok()?, end.trim().parse().ok()?),
+            None => {
+                let index = part.trim().parse().ok()?;
+                (index, index)
+            }
+        };
+        if start == 0 || start > end || end > total {
+            return None;
+        }
+        for index in start..=end {
+            if !indexes.contains(&index) {
+                indexes.push(index);
+            }
+        }
+    }
+    Some(indexes)
+}
+
+///Formats duration as `m:ss` or `h:mm:ss`
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if h > 0 {
+        format!("{h}:{m:02}:{s:02}")
+    } else {
+        format!("{m}:{s:02}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_playlist_names_leaving_playlists_dir() {
+        assert!(check_playlist_name("mix").is_ok());
+        assert!(check_playlist_name("lo-fi beats").is_ok());
+        for name in ["", ".", "..", "../x", "a/b", "a\\b", "/etc"] {
+            assert!(check_playlist_name(name).is_err(), "{name:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn parses_duration_forms() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("45s").unwrap(), Duration::from_secs(45));
+        assert_eq!(parse_duration("1h30m").unwrap(), Duration::from_secs(5400));
+        assert_eq!(parse_duration("1:23").unwrap(), Duration::from_secs(83));
+        assert_eq!(
+            parse_duration("1:02:03").unwrap(),
+            Duration::from_secs(3723)
+        );
+        assert_eq!(
+            parse_duration(" 1.5 ").unwrap(),
+            Duration::from_millis(1500)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        for input in [
+            "", "abc", "10x", "5m3", "-1", "1:-2", "inf", "-inf", "NaN", "1e20", "1:inf",
+        ] {
+            assert!(parse_duration(input).is_err(), "{input:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn parses_selection_ranges() {
+        assert_eq!(parse_selection("all", 3), Some(vec![1, 2, 3]));
+        assert_eq!(parse_selection(" 2 ", 3), Some(vec![2]));
+        assert_eq!(parse_selection("1-3,5", 5), Some(vec![1, 2, 3, 5]));
+        assert_eq!(parse_selection("3, 1-3", 5), Some(vec![3, 1, 2]));
+    }
+
+    #[test]
+    fn rejects_invalid_selections() {
+        assert_eq!(parse_selection("0", 3), None);
+        assert_eq!(parse_selection("4", 3), None);
+        assert_eq!(parse_selection("3-1", 3), None);
+        assert_eq!(parse_selection("1-", 3), None);
+        assert_eq!(parse_selection("1,,2", 3), None);
+        assert_eq!(parse_selection("x", 3), None);
     }
 }